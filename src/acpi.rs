@@ -0,0 +1,304 @@
+// This module locates the ACPI (Advanced Configuration and Power Interface) tables that the
+// firmware leaves in memory and parses the ones the kernel cares about. For now that is only the
+// MADT (Multiple APIC Description Table), which describes the interrupt controllers of the
+// machine.
+
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::{mem, ptr, slice};
+use x86_64::PhysAddr;
+
+use crate::memory::phys_to_virt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    // no RSDP was found in the BIOS memory areas
+    RsdpNotFound,
+    // a table was found, but its checksum does not add up to zero
+    InvalidChecksum,
+    // the RSDT/XSDT does not contain a table with the requested signature
+    TableNotFound,
+}
+
+// The Root System Description Pointer is the entry point to the ACPI tables. On BIOS systems it
+// lives somewhere in the first KiB of the EBDA or in the BIOS ROM area and always starts with the
+// "RSD PTR " signature.
+#[repr(C, packed)]
+#[allow(dead_code)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // the fields below only exist from revision 2 (ACPI 2.0) onwards
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+// Every ACPI table (except the RSDP) starts with this header.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+// The RSDT contains 32 bit pointers to the other tables, the XSDT (ACPI 2.0+) 64 bit ones.
+struct RootTable {
+    address: PhysAddr,
+    entry_size: usize,
+}
+
+static ROOT_TABLE: OnceCell<RootTable> = OnceCell::uninit();
+static MADT: OnceCell<Madt> = OnceCell::uninit();
+
+// Locates the RSDP and parses the MADT.
+//
+// Must be called after the heap and `memory::init` were initialized, since the tables are read
+// through the physical memory mapping and the parsed entries are stored in `Vec`s.
+pub fn init() -> Result<(), AcpiError> {
+    let rsdp_addr = find_rsdp().ok_or(AcpiError::RsdpNotFound)?;
+    let rsdp = unsafe { read_phys::<Rsdp>(rsdp_addr) };
+
+    let root = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        RootTable {
+            address: PhysAddr::new(rsdp.xsdt_address),
+            entry_size: 8,
+        }
+    } else {
+        RootTable {
+            address: PhysAddr::new(rsdp.rsdt_address as u64),
+            entry_size: 4,
+        }
+    };
+    if !unsafe { table_is_valid(root.address) } {
+        return Err(AcpiError::InvalidChecksum);
+    }
+    ROOT_TABLE
+        .try_init_once(|| root)
+        .expect("acpi::init should only be called once");
+
+    let madt_addr = find_table(b"APIC")?;
+    let madt = unsafe { Madt::parse(madt_addr) };
+    MADT.try_init_once(|| madt)
+        .expect("acpi::init should only be called once");
+    Ok(())
+}
+
+// Returns the parsed MADT, or `None` if `init` was not called or failed.
+pub fn madt() -> Option<&'static Madt> {
+    MADT.try_get().ok()
+}
+
+// Returns the physical address of the table with the given signature (e.g. `b"HPET"`).
+pub fn find_table(signature: &[u8; 4]) -> Result<PhysAddr, AcpiError> {
+    let root = ROOT_TABLE.try_get().map_err(|_| AcpiError::TableNotFound)?;
+    let header = unsafe { read_phys::<SdtHeader>(root.address) };
+    let entry_count = (header.length as usize - mem::size_of::<SdtHeader>()) / root.entry_size;
+    let entries = root.address + mem::size_of::<SdtHeader>();
+
+    for i in 0..entry_count {
+        let entry_addr = entries + i * root.entry_size;
+        let table_addr = unsafe {
+            if root.entry_size == 8 {
+                read_phys::<u64>(entry_addr)
+            } else {
+                read_phys::<u32>(entry_addr) as u64
+            }
+        };
+        let table_addr = PhysAddr::new(table_addr);
+        let table = unsafe { read_phys::<SdtHeader>(table_addr) };
+        if &table.signature == signature {
+            if !unsafe { table_is_valid(table_addr) } {
+                return Err(AcpiError::InvalidChecksum);
+            }
+            return Ok(table_addr);
+        }
+    }
+    Err(AcpiError::TableNotFound)
+}
+
+// Reads a `T` from the given physical address.
+//
+// This function is unsafe because the caller must guarantee that the physical memory at `addr`
+// is mapped (see `memory::phys_to_virt`) and holds a valid `T`.
+pub unsafe fn read_phys<T>(addr: PhysAddr) -> T {
+    let ptr: *const T = phys_to_virt(addr).as_ptr();
+    // ACPI structures are packed, so the fields can be at any alignment
+    ptr::read_unaligned(ptr)
+}
+
+// Returns a byte slice over `len` bytes of physical memory starting at `addr`.
+unsafe fn phys_bytes(addr: PhysAddr, len: usize) -> &'static [u8] {
+    slice::from_raw_parts(phys_to_virt(addr).as_ptr(), len)
+}
+
+// All bytes of an ACPI structure, including its checksum field, must add up to zero.
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+unsafe fn table_is_valid(addr: PhysAddr) -> bool {
+    let header = read_phys::<SdtHeader>(addr);
+    checksum_ok(phys_bytes(addr, header.length as usize))
+}
+
+// Searches the first KiB of the Extended BIOS Data Area and the BIOS ROM area (0xE0000 to
+// 0xFFFFF) for the RSDP. The signature is always 16 byte aligned.
+fn find_rsdp() -> Option<PhysAddr> {
+    // the real mode segment of the EBDA is stored at 0x40E in the BIOS data area
+    let ebda_start = (unsafe { read_phys::<u16>(PhysAddr::new(0x40e)) } as u64) << 4;
+    let regions = [(ebda_start, ebda_start + 1024), (0xe0000, 0x100000)];
+
+    for &(start, end) in regions.iter() {
+        if start == 0 {
+            continue;
+        }
+        for addr in (start..end).step_by(16) {
+            let addr = PhysAddr::new(addr);
+            let bytes = unsafe { phys_bytes(addr, 20) };
+            // the checksum of revision 1 only covers the first 20 bytes
+            if &bytes[..8] == b"RSD PTR " && checksum_ok(bytes) {
+                return Some(addr);
+            }
+        }
+    }
+    None
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicEntry {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    // the processor can be brought up (either already enabled, or online capable)
+    pub usable: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: PhysAddr,
+    // the first global system interrupt (GSI) handled by this I/O APIC
+    pub gsi_base: u32,
+}
+
+// Describes an ISA IRQ that is not identity mapped to the GSI with the same number, or that does
+// not use the ISA default polarity (active high) and trigger mode (edge).
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptSourceOverride {
+    pub isa_irq: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+// Describes which local APIC LINT pin is wired to the NMI line.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmi {
+    // 0xff means all processors
+    pub processor_id: u8,
+    pub lint: u8,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    // the system also has the two legacy 8259 PICs, which have to be disabled
+    pub pcat_compat: bool,
+    pub processors: Vec<LocalApicEntry>,
+    pub io_apics: Vec<IoApicEntry>,
+    pub overrides: Vec<InterruptSourceOverride>,
+    pub nmis: Vec<LocalApicNmi>,
+}
+
+impl Madt {
+    // Parses the variable length entries that follow the fixed part of the MADT.
+    //
+    // This function is unsafe because the caller must guarantee that `addr` points to a valid
+    // MADT.
+    unsafe fn parse(addr: PhysAddr) -> Madt {
+        let header = read_phys::<SdtHeader>(addr);
+        let fixed = addr + mem::size_of::<SdtHeader>();
+        let mut madt = Madt {
+            local_apic_address: PhysAddr::new(read_phys::<u32>(fixed) as u64),
+            pcat_compat: read_phys::<u32>(fixed + 4u64) & 1 != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            nmis: Vec::new(),
+        };
+
+        let table = phys_bytes(addr, header.length as usize);
+        let mut offset = mem::size_of::<SdtHeader>() + 8;
+        while offset + 2 <= table.len() {
+            let entry_type = table[offset];
+            let entry_len = table[offset + 1] as usize;
+            if entry_len < 2 || offset + entry_len > table.len() {
+                break;
+            }
+            let entry = addr + offset;
+            match entry_type {
+                0 => madt.processors.push(LocalApicEntry {
+                    processor_id: read_phys::<u8>(entry + 2u64),
+                    apic_id: read_phys::<u8>(entry + 3u64),
+                    usable: read_phys::<u32>(entry + 4u64) & 0b11 != 0,
+                }),
+                1 => madt.io_apics.push(IoApicEntry {
+                    id: read_phys::<u8>(entry + 2u64),
+                    address: PhysAddr::new(read_phys::<u32>(entry + 4u64) as u64),
+                    gsi_base: read_phys::<u32>(entry + 8u64),
+                }),
+                2 => {
+                    let flags = read_phys::<u16>(entry + 8u64);
+                    madt.overrides.push(InterruptSourceOverride {
+                        isa_irq: read_phys::<u8>(entry + 3u64),
+                        gsi: read_phys::<u32>(entry + 4u64),
+                        active_low: flags & 0b11 == 0b11,
+                        level_triggered: (flags >> 2) & 0b11 == 0b11,
+                    })
+                }
+                4 => {
+                    let flags = read_phys::<u16>(entry + 3u64);
+                    madt.nmis.push(LocalApicNmi {
+                        processor_id: read_phys::<u8>(entry + 2u64),
+                        lint: read_phys::<u8>(entry + 5u64),
+                        active_low: flags & 0b11 == 0b11,
+                        level_triggered: (flags >> 2) & 0b11 == 0b11,
+                    })
+                }
+                // the 64 bit local APIC address override
+                5 => madt.local_apic_address = PhysAddr::new(read_phys::<u64>(entry + 4u64)),
+                _ => {}
+            }
+            offset += entry_len;
+        }
+        madt
+    }
+
+    // Returns the GSI and flags that the given ISA IRQ is connected to, honouring the interrupt
+    // source overrides. ISA IRQs default to being identity mapped, active high and edge triggered.
+    pub fn isa_irq_route(&self, isa_irq: u8) -> InterruptSourceOverride {
+        self.overrides
+            .iter()
+            .find(|o| o.isa_irq == isa_irq)
+            .copied()
+            .unwrap_or(InterruptSourceOverride {
+                isa_irq,
+                gsi: isa_irq as u32,
+                active_low: false,
+                level_triggered: false,
+            })
+    }
+}
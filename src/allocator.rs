@@ -2,7 +2,6 @@ pub mod bump;
 pub mod fixed_size_block;
 
 use linked_list_allocator::LockedHeap;
-use spin::MutexGuard;
 use x86_64::{
     structures::paging::{
         mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
@@ -11,11 +10,17 @@ use x86_64::{
 };
 
 use self::bump::BumpAllocator;
+use crate::sync::{IrqSafeMutex, IrqSafeMutexGuard};
+use crate::time::tsc::CycleCounter;
 
 // calling Box::new() will use this allocator to allocate and deallocate dynamic memory (from the Heap region)
 #[global_allocator]
 static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
 
+// TSC cycles spent in `alloc` and `dealloc` of the allocators, for benchmarking
+pub static ALLOC_CYCLES: CycleCounter = CycleCounter::new();
+pub static DEALLOC_CYCLES: CycleCounter = CycleCounter::new();
+
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024;
 
@@ -46,9 +51,10 @@ pub fn init_heap(
     Ok(())
 }
 
-// a wrapper around spin::Mutex to permit trait implementations.
+// a wrapper around a mutex to permit trait implementations. Interrupt handlers might allocate
+// too, so the lock keeps interrupts disabled while it is held.
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: IrqSafeMutex<A>,
 }
 
 impl<A> Locked<A> {
@@ -56,10 +62,10 @@ impl<A> Locked<A> {
     // ALLOCATOR
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: spin::Mutex::new(inner),
+            inner: IrqSafeMutex::new(inner),
         }
     }
-    pub fn lock(&self) -> MutexGuard<A> {
+    pub fn lock(&self) -> IrqSafeMutexGuard<'_, A> {
         self.inner.lock()
     }
 }
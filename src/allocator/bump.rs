@@ -1,6 +1,6 @@
 use core::{alloc::GlobalAlloc, ptr};
 
-use super::{align_up, Locked};
+use super::{align_up, Locked, ALLOC_CYCLES, DEALLOC_CYCLES};
 
 /// A Bump Allocator is a very simple allocator that only allows the heap to grow linearly.
 /// `next` will always point to the boundary between used and unused memory.
@@ -52,6 +52,7 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
     // the allocator as a static variable and static variables are immutable.
     // To get around this problem, we wrap our BumpAllocator type in a Locked type.
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        let _measure = ALLOC_CYCLES.start();
         let mut bmp_allocator = self.lock();
 
         let alloc_start = align_up(bmp_allocator.next, layout.align());
@@ -69,6 +70,7 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
         }
     }
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        let _measure = DEALLOC_CYCLES.start();
         let mut bump = self.lock(); // get a mutable reference
 
         bump.allocations -= 1;
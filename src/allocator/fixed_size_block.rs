@@ -54,12 +54,13 @@ fn list_index(layout: &Layout) -> Option<usize> {
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
-use super::Locked;
+use super::{Locked, ALLOC_CYCLES, DEALLOC_CYCLES};
 use alloc::alloc::GlobalAlloc;
 use core::{mem, ptr::NonNull};
 
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _measure = ALLOC_CYCLES.start();
         let mut allocator = self.lock();
         match list_index(&layout) {
             Some(index) => {
@@ -83,6 +84,7 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _measure = DEALLOC_CYCLES.start();
         let mut allocator = self.lock();
         match list_index(&layout) {
             Some(index) => {
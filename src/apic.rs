@@ -0,0 +1,180 @@
+// This module replaces the legacy 8259 PICs with the APIC (Advanced Programmable Interrupt
+// Controller) architecture: a local APIC in every CPU core and one or more I/O APICs that route
+// the device interrupts to them. The layout of these controllers is described by the MADT, see
+// the `acpi` module.
+
+pub mod ioapic;
+pub mod lapic;
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Size4KiB};
+
+use self::ioapic::IoApic;
+use crate::acpi::{self, AcpiError};
+use crate::interrupts::{self, InterruptsIndex, PIC_1_OFFSET};
+use crate::{memory, time};
+
+// The local APIC delivers this vector when an interrupt disappears before the CPU accepted it.
+// Such an interrupt must not be acknowledged with an EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// number of the ISA IRQs (the ones the legacy PICs used to handle)
+const ISA_IRQS: u8 = 16;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static IO_APICS: spin::Mutex<Vec<IoApic>> = spin::Mutex::new(Vec::new());
+
+#[derive(Debug)]
+pub enum ApicError {
+    // the CPU has no local APIC
+    NotSupported,
+    // the MADT could not be found
+    Acpi(AcpiError),
+    // the MADT does not list any I/O APIC
+    NoIoApic,
+    // the registers could not be mapped
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for ApicError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        ApicError::Map(err)
+    }
+}
+
+// Returns whether the APICs are in use (instead of the legacy PICs).
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// Switches interrupt handling from the legacy PICs to the APICs: masks the PICs, enables the local
+// APIC, routes the ISA IRQs through the I/O APIC to the same vectors the PICs used
+// (`PIC_1_OFFSET + irq`) and replaces the PIT with the local APIC timer.
+//
+// Must be called after `acpi::init`. If an error is returned, the legacy PICs stay in charge. The
+// APIC registers might already be mapped then, the mappings are kept (but not used).
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), ApicError> {
+    if !lapic::is_supported() {
+        return Err(ApicError::NotSupported);
+    }
+    let madt = acpi::madt().ok_or(ApicError::Acpi(AcpiError::TableNotFound))?;
+    if madt.io_apics.is_empty() {
+        return Err(ApicError::NoIoApic);
+    }
+
+    let lapic_base =
+        unsafe { memory::map_mmio(madt.local_apic_address, 4096, mapper, frame_allocator)? };
+    let mut io_apics = Vec::new();
+    for entry in madt.io_apics.iter() {
+        let base = unsafe { memory::map_mmio(entry.address, 0x20, mapper, frame_allocator)? };
+        io_apics.push(unsafe { IoApic::new(base, entry.gsi_base) });
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if madt.pcat_compat {
+            interrupts::disable_pics();
+        }
+
+        unsafe { lapic::init(lapic_base, SPURIOUS_VECTOR) };
+        let apic_id = lapic::id();
+        set_nmis(madt);
+
+        for io_apic in io_apics.iter_mut() {
+            io_apic.mask_all();
+        }
+        // every ISA IRQ is routed (masked) to the vector the PIC would have used for it, so the
+        // existing handlers keep working
+        for irq in 0..ISA_IRQS {
+            let route = madt.isa_irq_route(irq);
+            // e.g. the PIT is usually wired to GSI 2, which makes IRQ 2 (the old PIC cascade)
+            // unreachable
+            if madt
+                .overrides
+                .iter()
+                .any(|o| o.gsi == route.gsi && o.isa_irq != irq)
+            {
+                continue;
+            }
+            if let Some(io_apic) = io_apics.iter_mut().find(|a| a.handles(route.gsi)) {
+                io_apic.set_redirection(
+                    route.gsi,
+                    PIC_1_OFFSET + irq,
+                    apic_id,
+                    route.active_low,
+                    route.level_triggered,
+                    true,
+                );
+            }
+        }
+        *IO_APICS.lock() = io_apics;
+        ENABLED.store(true, Ordering::Relaxed);
+
+        // the PIT (IRQ 0) stays masked, the local APIC timer takes over its vector and frequency
+        set_irq_masked(InterruptsIndex::Keyboard.as_irq(), false);
+        let frequency = time::frequency();
+        let nanos_per_tick = lapic::start_timer(InterruptsIndex::Timer.as_u8(), frequency);
+        time::set_tick_period(frequency, nanos_per_tick);
+    });
+    Ok(())
+}
+
+// Enables the local APIC of an application processor and starts its timer. The device interrupts
+// stay routed to the bootstrap processor.
+//
+// Must be called with interrupts disabled, after `init` succeeded on the bootstrap processor.
+pub fn init_ap() {
+    let madt = match acpi::madt() {
+        Some(madt) => madt,
+        None => return,
+    };
+    unsafe { lapic::init(lapic::base(), SPURIOUS_VECTOR) };
+    set_nmis(madt);
+    // the timer was already calibrated by the bootstrap processor, so this does not use the PIT
+    lapic::start_timer(InterruptsIndex::Timer.as_u8(), time::frequency());
+}
+
+// Configures the LINT pins of the current CPU's local APIC the MADT marks as NMI sources.
+fn set_nmis(madt: &acpi::Madt) {
+    let apic_id = lapic::id();
+    for nmi in madt.nmis.iter() {
+        // 0xff means all processors
+        if nmi.processor_id == 0xff
+            || madt
+                .processors
+                .iter()
+                .any(|p| p.processor_id == nmi.processor_id && p.apic_id == apic_id)
+        {
+            lapic::set_nmi(nmi.lint, nmi.active_low, nmi.level_triggered);
+        }
+    }
+}
+
+// Masks or unmasks the given ISA IRQ in the I/O APIC it is routed to.
+pub fn set_irq_masked(irq: u8, masked: bool) {
+    let gsi = match acpi::madt() {
+        Some(madt) => madt.isa_irq_route(irq).gsi,
+        None => return,
+    };
+    let mut io_apics = IO_APICS.lock();
+    if let Some(io_apic) = io_apics.iter_mut().find(|a| a.handles(gsi)) {
+        io_apic.set_masked(gsi, masked);
+    }
+}
+
+// Routes a global system interrupt that does not belong to an ISA IRQ (e.g. one of an HPET timer)
+// to `vector` on the current CPU, as an active high, edge triggered interrupt. Returns `false` if no
+// I/O APIC handles the GSI.
+pub fn route_gsi(gsi: u32, vector: u8) -> bool {
+    let mut io_apics = IO_APICS.lock();
+    match io_apics.iter_mut().find(|a| a.handles(gsi)) {
+        Some(io_apic) => {
+            io_apic.set_redirection(gsi, vector, lapic::id(), false, false, false);
+            true
+        }
+        None => false,
+    }
+}
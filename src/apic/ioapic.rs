@@ -0,0 +1,105 @@
+use core::ptr;
+
+use x86_64::VirtAddr;
+
+// The I/O APIC receives the interrupts of the external devices and forwards them to the local
+// APICs according to its redirection table. Each entry of that table corresponds to one global
+// system interrupt (GSI).
+//
+// Only two registers are memory mapped: IOREGSEL selects an internal register and IOWIN is used to
+// read or write the selected register.
+
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+// internal registers
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+// bits of the low half of a redirection entry
+const ACTIVE_LOW: u32 = 1 << 13;
+const LEVEL_TRIGGERED: u32 = 1 << 15;
+const MASKED: u32 = 1 << 16;
+
+pub struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    redirection_entries: u32,
+}
+
+impl IoApic {
+    // This function is unsafe because the caller must guarantee that `base` is the virtual address
+    // the I/O APIC registers are mapped at (uncached).
+    pub unsafe fn new(base: VirtAddr, gsi_base: u32) -> Self {
+        let mut io_apic = IoApic {
+            base,
+            gsi_base,
+            redirection_entries: 0,
+        };
+        // bits 16..24 of the version register hold the index of the last redirection entry
+        io_apic.redirection_entries = ((io_apic.read(IOAPICVER) >> 16) & 0xff) + 1;
+        io_apic
+    }
+
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.base + IOREGSEL).as_mut_ptr(), register);
+            ptr::read_volatile((self.base + IOWIN).as_ptr())
+        }
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.base + IOREGSEL).as_mut_ptr(), register);
+            ptr::write_volatile((self.base + IOWIN).as_mut_ptr(), value);
+        }
+    }
+
+    // Returns whether the given GSI is connected to this I/O APIC.
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.redirection_entries
+    }
+
+    // Masks every entry of the redirection table.
+    pub fn mask_all(&mut self) {
+        for entry in 0..self.redirection_entries {
+            self.write(IOREDTBL + entry * 2, MASKED);
+        }
+    }
+
+    // Routes the given GSI to `vector` on the CPU with the local APIC id `apic_id`.
+    pub fn set_redirection(
+        &mut self,
+        gsi: u32,
+        vector: u8,
+        apic_id: u8,
+        active_low: bool,
+        level_triggered: bool,
+        masked: bool,
+    ) {
+        let mut low = u32::from(vector);
+        if active_low {
+            low |= ACTIVE_LOW;
+        }
+        if level_triggered {
+            low |= LEVEL_TRIGGERED;
+        }
+        if masked {
+            low |= MASKED;
+        }
+        let register = IOREDTBL + (gsi - self.gsi_base) * 2;
+        // write the destination first so a half-written entry is never unmasked
+        self.write(register + 1, u32::from(apic_id) << 24);
+        self.write(register, low);
+    }
+
+    pub fn set_masked(&mut self, gsi: u32, masked: bool) {
+        let register = IOREDTBL + (gsi - self.gsi_base) * 2;
+        let low = self.read(register);
+        if masked {
+            self.write(register, low | MASKED);
+        } else {
+            self.write(register, low & !MASKED);
+        }
+    }
+}
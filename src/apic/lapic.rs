@@ -0,0 +1,216 @@
+use core::arch::x86_64::__cpuid;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::Msr;
+use x86_64::VirtAddr;
+
+use crate::time::pit;
+
+// Every CPU core has its own local APIC. It receives the interrupts routed to this core (from the
+// I/O APIC or from other cores) and also has a built-in timer.
+//
+// The registers are memory mapped and 16 byte aligned, each of them is 32 bits wide.
+
+const IA32_APIC_BASE_MSR: u32 = 0x1b;
+// bit 11 of the IA32_APIC_BASE MSR globally enables the local APIC
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// register offsets
+const ID: usize = 0x20;
+const TASK_PRIORITY: usize = 0x80;
+const EOI: usize = 0xb0;
+const SPURIOUS_INTERRUPT_VECTOR: usize = 0xf0;
+const ERROR_STATUS: usize = 0x280;
+const INTERRUPT_COMMAND_LOW: usize = 0x300;
+const INTERRUPT_COMMAND_HIGH: usize = 0x310;
+const LVT_TIMER: usize = 0x320;
+const LVT_LINT0: usize = 0x350;
+const LVT_LINT1: usize = 0x360;
+const LVT_ERROR: usize = 0x370;
+const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_CURRENT_COUNT: usize = 0x390;
+const TIMER_DIVIDE_CONFIG: usize = 0x3e0;
+
+// bits of the local vector table (LVT) entries
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+const LVT_LEVEL_TRIGGERED: u32 = 1 << 15;
+
+// bits of the interrupt command register (ICR)
+const ICR_DELIVERY_FIXED: u32 = 0b000 << 8;
+const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+// the destination shorthand "all excluding self", the destination field is ignored then
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+// bit 8 of the spurious interrupt vector register software-enables the local APIC
+const SVR_ENABLE: u32 = 1 << 8;
+// the timer counts down at the bus frequency divided by 16
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+// virtual address the local APIC registers are mapped at, set by `init`
+static BASE: AtomicU64 = AtomicU64::new(0);
+// rate at which the timer counts down, measured by the first call to `start_timer`
+static TIMER_TICKS_PER_SECOND: AtomicU64 = AtomicU64::new(0);
+
+// CPUID leaf 1 reports whether the processor has an on-chip local APIC in bit 9 of EDX.
+pub fn is_supported() -> bool {
+    let result = unsafe { __cpuid(1) };
+    result.edx & (1 << 9) != 0
+}
+
+unsafe fn read(register: usize) -> u32 {
+    let addr = BASE.load(Ordering::Relaxed) as usize + register;
+    ptr::read_volatile(addr as *const u32)
+}
+
+unsafe fn write(register: usize, value: u32) {
+    let addr = BASE.load(Ordering::Relaxed) as usize + register;
+    ptr::write_volatile(addr as *mut u32, value);
+}
+
+// Returns the virtual address the local APIC registers are mapped at. It is the same on every
+// CPU, each one sees its own local APIC there.
+pub fn base() -> VirtAddr {
+    VirtAddr::new(BASE.load(Ordering::Relaxed))
+}
+
+// Enables the local APIC of the current CPU, with all local interrupt sources masked.
+//
+// This function is unsafe because the caller must guarantee that `base` is the virtual address
+// the local APIC registers are mapped at (uncached), and that interrupts are disabled.
+pub unsafe fn init(base: VirtAddr, spurious_vector: u8) {
+    BASE.store(base.as_u64(), Ordering::Relaxed);
+
+    let mut apic_base = Msr::new(IA32_APIC_BASE_MSR);
+    let value = apic_base.read();
+    apic_base.write(value | APIC_BASE_ENABLE);
+
+    // accept interrupts of all priorities
+    write(TASK_PRIORITY, 0);
+    write(LVT_TIMER, LVT_MASKED);
+    write(LVT_LINT0, LVT_MASKED);
+    write(LVT_LINT1, LVT_MASKED);
+    write(LVT_ERROR, LVT_MASKED);
+
+    write(
+        SPURIOUS_INTERRUPT_VECTOR,
+        SVR_ENABLE | u32::from(spurious_vector),
+    );
+
+    // the error status register has to be written before it can be read, clear it twice to drop
+    // any errors that happened before we were enabled
+    write(ERROR_STATUS, 0);
+    write(ERROR_STATUS, 0);
+    end_of_interrupt();
+}
+
+// Returns the id of the local APIC of the current CPU.
+pub fn id() -> u8 {
+    (unsafe { read(ID) } >> 24) as u8
+}
+
+// Configures the given LINT pin (0 or 1) to deliver a non-maskable interrupt (NMI).
+pub fn set_nmi(lint: u8, active_low: bool, level_triggered: bool) {
+    let mut entry = LVT_DELIVERY_NMI;
+    if active_low {
+        entry |= LVT_ACTIVE_LOW;
+    }
+    if level_triggered {
+        entry |= LVT_LEVEL_TRIGGERED;
+    }
+    let register = if lint == 0 { LVT_LINT0 } else { LVT_LINT1 };
+    unsafe { write(register, entry) };
+}
+
+// Signals the end of the current interrupt to the local APIC.
+pub fn end_of_interrupt() {
+    unsafe { write(EOI, 0) };
+}
+
+// Starts the local APIC timer in periodic mode, firing `vector` `frequency` times per second, and
+// returns the actual length of a tick in nanoseconds.
+//
+// The timer runs at the (unknown) bus frequency, so it is first calibrated against the PIT.
+pub fn start_timer(vector: u8, frequency: u32) -> u64 {
+    unsafe {
+        write(TIMER_DIVIDE_CONFIG, TIMER_DIVIDE_BY_16);
+        write(LVT_TIMER, LVT_MASKED);
+
+        let mut ticks_per_second = TIMER_TICKS_PER_SECOND.load(Ordering::Relaxed);
+        if ticks_per_second == 0 {
+            // count down from the maximum value for 10ms
+            write(TIMER_INITIAL_COUNT, u32::MAX);
+            pit::busy_wait_us(10_000);
+            let ticks_per_10ms = u32::MAX - read(TIMER_CURRENT_COUNT);
+            ticks_per_second = ticks_per_10ms as u64 * 100;
+            TIMER_TICKS_PER_SECOND.store(ticks_per_second, Ordering::Relaxed);
+        }
+
+        let initial_count = (ticks_per_second / frequency as u64).clamp(1, u32::MAX as u64);
+        write(LVT_TIMER, LVT_TIMER_PERIODIC | u32::from(vector));
+        write(TIMER_INITIAL_COUNT, initial_count as u32);
+
+        initial_count * 1_000_000_000 / ticks_per_second.max(1)
+    }
+}
+
+// Sends an interrupt command to the local APIC with the given id and waits until it was accepted.
+fn send_command(apic_id: u8, command: u32) {
+    // an interrupt handler sending an IPI between the two writes would overwrite the destination
+    interrupts::without_interrupts(|| unsafe {
+        write(INTERRUPT_COMMAND_HIGH, u32::from(apic_id) << 24);
+        // writing the low half sends the interrupt
+        write(INTERRUPT_COMMAND_LOW, command);
+        while read(INTERRUPT_COMMAND_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+// Sends an inter-processor interrupt (IPI) with the given vector to the CPU with the given local
+// APIC id.
+pub fn send_ipi(apic_id: u8, vector: u8) {
+    send_command(apic_id, ICR_DELIVERY_FIXED | u32::from(vector));
+}
+
+// Sends an IPI with the given vector to every CPU except the current one.
+pub fn broadcast_ipi(vector: u8) {
+    send_command(
+        0,
+        ICR_ALL_EXCLUDING_SELF | ICR_DELIVERY_FIXED | u32::from(vector),
+    );
+}
+
+// Sends a non-maskable interrupt to the CPU with the given local APIC id. It arrives even if the
+// CPU has interrupts disabled.
+pub fn send_nmi(apic_id: u8) {
+    send_command(apic_id, ICR_DELIVERY_NMI);
+}
+
+// Sends a non-maskable interrupt to every CPU except the current one.
+pub fn broadcast_nmi() {
+    send_command(0, ICR_ALL_EXCLUDING_SELF | ICR_DELIVERY_NMI);
+}
+
+// Sends an INIT IPI, which resets the CPU with the given local APIC id into a wait-for-startup
+// state.
+pub fn send_init_ipi(apic_id: u8) {
+    send_command(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+}
+
+// Sends a startup IPI (SIPI), making a CPU waiting after an INIT IPI start executing in real mode
+// at physical address `page * 4096`.
+pub fn send_startup_ipi(apic_id: u8, page: u8) {
+    send_command(
+        apic_id,
+        ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | u32::from(page),
+    );
+}
@@ -0,0 +1,192 @@
+// Line-oriented input from the keyboard, echoed to the VGA console. `read_line` buffers the
+// keystrokes until Enter is pressed and lets the user edit the line in the meantime:
+//
+// - Backspace and Delete remove the character before and under the cursor
+// - Left, Right, Home and End move the cursor
+// - Ctrl+U removes everything before the cursor, Ctrl+W the word before it
+
+use alloc::{string::String, vec::Vec};
+use futures_util::StreamExt;
+use x86_64::instructions::interrupts;
+
+use crate::task::keyboard::{KeyCode, KeyEvent, KeyEventStream};
+use crate::task::sync::Mutex;
+use crate::vga_buffer::{Writer, BUFFER_HEIGHT, BUFFER_WIDTH, WRITER};
+
+// control characters produced by `HandleControl::MapLettersToUnicode`
+const BACKSPACE: char = '\u{8}';
+const DELETE: char = '\u{7f}';
+const CTRL_U: char = '\u{15}';
+const CTRL_W: char = '\u{17}';
+
+// Created by the first `read_line`. There can only be one `KeyEventStream`, so the keyboard must
+// not be read in any other way once the console is used.
+static KEY_EVENTS: Mutex<Option<KeyEventStream>> = Mutex::new(None);
+
+// Reads a line from the keyboard, without the final newline. The line is echoed where the output
+// currently is, so a prompt can be printed right before.
+//
+// Concurrent calls read one line after the other.
+pub async fn read_line() -> String {
+    let mut events = KEY_EVENTS.lock().await;
+    let events = events.get_or_insert_with(KeyEventStream::new);
+    let mut editor = LineEditor::new();
+    while let Some(event) = events.next().await {
+        if event.is_pressed() && editor.handle(&event) == Edit::Done {
+            break;
+        }
+    }
+    editor.line.into_iter().collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edit {
+    Continue,
+    Done,
+}
+
+struct LineEditor {
+    line: Vec<char>,
+    // index into `line`
+    cursor: usize,
+    // where the line starts on the screen, counted in characters from the top left corner. It
+    // moves up whenever the screen scrolls.
+    start: usize,
+}
+
+impl LineEditor {
+    fn new() -> Self {
+        let start = interrupts::without_interrupts(|| offset(&WRITER.lock()));
+        LineEditor {
+            line: Vec::new(),
+            cursor: 0,
+            start,
+        }
+    }
+
+    fn handle(&mut self, event: &KeyEvent) -> Edit {
+        // the keys of the keypad only move the cursor while Num Lock is off, they then produce no
+        // character
+        match (event.code, event.character) {
+            (KeyCode::Enter | KeyCode::NumpadEnter, _) | (_, Some('\n')) => {
+                self.finish();
+                return Edit::Done;
+            }
+            (KeyCode::ArrowLeft, _) | (KeyCode::Numpad4, None) => {
+                self.move_to(self.cursor.saturating_sub(1))
+            }
+            (KeyCode::ArrowRight, _) | (KeyCode::Numpad6, None) => {
+                self.move_to((self.cursor + 1).min(self.line.len()))
+            }
+            (KeyCode::Home, _) | (KeyCode::Numpad7, None) => self.move_to(0),
+            (KeyCode::End, _) | (KeyCode::Numpad1, None) => self.move_to(self.line.len()),
+            (KeyCode::Delete, _) | (KeyCode::NumpadPeriod, None) | (_, Some(DELETE)) => {
+                if self.cursor < self.line.len() {
+                    self.remove(self.cursor, self.cursor + 1);
+                }
+            }
+            (_, Some(BACKSPACE)) => {
+                if self.cursor > 0 {
+                    self.remove(self.cursor - 1, self.cursor);
+                }
+            }
+            (_, Some(CTRL_U)) => self.remove(0, self.cursor),
+            (_, Some(CTRL_W)) => self.remove(self.word_start(), self.cursor),
+            // the other control characters, e.g. Ctrl+C, are ignored
+            (_, Some(character)) if !character.is_control() || character == '\t' => {
+                self.insert(character)
+            }
+            _ => {}
+        }
+        Edit::Continue
+    }
+
+    // where the word before the cursor starts, skipping the whitespace right before the cursor
+    fn word_start(&self) -> usize {
+        let before = &self.line[..self.cursor];
+        let end = before
+            .iter()
+            .rposition(|c| !c.is_whitespace())
+            .map_or(0, |i| i + 1);
+        before[..end]
+            .iter()
+            .rposition(|c| c.is_whitespace())
+            .map_or(0, |i| i + 1)
+    }
+
+    fn insert(&mut self, character: char) {
+        self.line.insert(self.cursor, character);
+        self.cursor += 1;
+        self.redraw(self.cursor - 1, 0);
+    }
+
+    // Removes the characters from `from` up to `to` and moves the cursor to `from`.
+    fn remove(&mut self, from: usize, to: usize) {
+        if from == to {
+            return;
+        }
+        let at_end = to == self.line.len();
+        self.line.drain(from..to);
+        self.cursor = from;
+        if at_end && to - from == 1 {
+            // the common case of deleting the last character needs no redraw
+            interrupts::without_interrupts(|| WRITER.lock().backspace());
+        } else {
+            self.redraw(from, to - from);
+        }
+    }
+
+    fn move_to(&mut self, cursor: usize) {
+        self.cursor = cursor;
+        interrupts::without_interrupts(|| set_offset(&mut WRITER.lock(), self.start + cursor));
+    }
+
+    // Moves the output to the end of the line and starts a new one.
+    fn finish(&mut self) {
+        self.move_to(self.line.len());
+        interrupts::without_interrupts(|| WRITER.lock().write_byte(b'\n'));
+    }
+
+    // Writes the line again from `from` on, blanks the `erased` characters that were behind its
+    // end and puts the output back at the cursor.
+    fn redraw(&mut self, from: usize, erased: usize) {
+        interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            set_offset(&mut writer, self.start + from);
+            for &character in &self.line[from..] {
+                writer.write_byte(screen_byte(character));
+            }
+            for _ in 0..erased {
+                writer.write_byte(b' ');
+            }
+            // writing past the last row scrolled the screen, which moved the start of the line up
+            self.start = offset(&writer).saturating_sub(self.line.len() + erased);
+            set_offset(&mut writer, self.start + self.cursor);
+        });
+    }
+}
+
+// a character as it appears on the screen, like `Writer::write_string` shows it
+fn screen_byte(character: char) -> u8 {
+    match character {
+        ' '..='~' => character as u8,
+        // the cell is blanked
+        '\t' => b' ',
+        _ => 0xfe,
+    }
+}
+
+fn offset(writer: &Writer) -> usize {
+    let (row, column) = writer.position();
+    row * BUFFER_WIDTH + column
+}
+
+fn set_offset(writer: &mut Writer, offset: usize) {
+    let offset = offset.min(BUFFER_HEIGHT * BUFFER_WIDTH);
+    // the end of the last row is only reachable as a full row
+    if offset == BUFFER_HEIGHT * BUFFER_WIDTH {
+        writer.set_position(BUFFER_HEIGHT - 1, BUFFER_WIDTH);
+    } else {
+        writer.set_position(offset / BUFFER_WIDTH, offset % BUFFER_WIDTH);
+    }
+}
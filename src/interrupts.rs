@@ -1,12 +1,16 @@
+use crate::hlt_loop;
+use crate::sync::IrqSafeMutex;
 use crate::task::keyboard::add_scancode;
-use crate::{gdt, println};
-use crate::{hlt_loop, print};
+use crate::task::mouse::add_mouse_byte;
+use crate::task::timer::wake_expired_timers;
+use crate::{apic, gdt, percpu, println, ps2, rtc, smp, thread, time};
+use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
-use spin;
 use x86_64::instructions::port::Port;
 use x86_64::structures::idt::{
-    InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode, PageFaultHandlerFunc,
+    HandlerFunc, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
+    PageFaultHandlerFunc,
 };
 
 #[derive(Debug, Clone, Copy)]
@@ -14,16 +18,28 @@ use x86_64::structures::idt::{
 pub enum InterruptsIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    Rtc = PIC_2_OFFSET,
+    Mouse = PIC_2_OFFSET + 4,
+    // not an ISA IRQ, the HPET event timer is routed through the I/O APIC
+    Hpet = PIC_2_OFFSET + 8,
+    // inter-processor interrupts, sent by the local APICs of the other CPUs. The priority class of
+    // a vector is its upper nibble, so they preempt the device interrupts.
+    TlbShootdown = 0xf0,
 }
 
 impl InterruptsIndex {
-    fn as_u8(self) -> u8 {
+    pub fn as_u8(self) -> u8 {
         self as u8
     }
 
     fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
+
+    // the ISA IRQ line this interrupt arrives on
+    pub fn as_irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
+    }
 }
 
 // PICs by default are configured to send interrupt codes starting from 1 which will conflict with
@@ -35,16 +51,128 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 // There will be two PICs (Programmable Interrupt Controllers), primary and secondary, and they will be
 // connected to the I/O Ports. This crate (pic8259) is just an abstraction for working with
 // the PICs.
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: IrqSafeMutex<ChainedPics> =
+    IrqSafeMutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+// command ports of the PICs
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xa0;
+// data ports of the PICs, writing to them sets the interrupt mask (a set bit masks that IRQ)
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_DATA: u16 = 0xa1;
+
+// A PIC raises its lowest priority IRQ (7 for the primary, 15 for the secondary) when an interrupt
+// disappears before the CPU acknowledged it, e.g. because of electrical noise on the IRQ line.
+const PIC_1_SPURIOUS_VECTOR: u8 = PIC_1_OFFSET + 7;
+const PIC_2_SPURIOUS_VECTOR: u8 = PIC_2_OFFSET + 7;
+
+// OCW3 command that makes the next read of the command port return the In-Service Register
+const PIC_READ_ISR: u8 = 0x0b;
+// end of interrupt command
+const PIC_EOI: u8 = 0x20;
+
+static SPURIOUS_IRQS: AtomicU64 = AtomicU64::new(0);
+
+// number of interrupts received on every vector that has no handler of its own
+static UNHANDLED_INTERRUPTS: [AtomicU64; 256] = {
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; 256]
+};
+
+// Masks every IRQ of both PICs. This is used when the APIC takes over, since the PICs would
+// otherwise still deliver (now unexpected) interrupts.
+pub fn disable_pics() {
+    let _pics = PICS.lock();
+    unsafe {
+        Port::<u8>::new(PIC_1_DATA).write(0xff);
+        Port::<u8>::new(PIC_2_DATA).write(0xff);
+    }
+}
+
+// Masks or unmasks the given ISA IRQ, on whichever interrupt controller is in use.
+pub fn set_irq_masked(irq: u8, masked: bool) {
+    if apic::is_enabled() {
+        apic::set_irq_masked(irq, masked);
+        return;
+    }
+
+    let _pics = PICS.lock();
+    let (mut port, bit) = if irq < 8 {
+        (Port::<u8>::new(PIC_1_DATA), irq)
+    } else {
+        (Port::<u8>::new(PIC_2_DATA), irq - 8)
+    };
+    unsafe {
+        let mask = port.read();
+        if masked {
+            port.write(mask | (1 << bit));
+        } else {
+            port.write(mask & !(1 << bit));
+        }
+        // the secondary PIC reaches the CPU through IRQ 2 of the primary one
+        if irq >= 8 && !masked {
+            let mut primary = Port::<u8>::new(PIC_1_DATA);
+            let mask = primary.read();
+            primary.write(mask & !(1 << 2));
+        }
+    }
+}
+
+// The interrupt controller expects us to send an `end of interrupt (EOI)` signal from the handler,
+// otherwise it will not deliver any further interrupts.
+fn notify_end_of_interrupt(vector: u8) {
+    if apic::is_enabled() {
+        apic::lapic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(vector) };
+    }
+}
+
+// Returns whether the given IRQ is actually being serviced by the PIC, by reading its In-Service
+// Register. If it is not, the IRQ was spurious.
+fn pic_irq_in_service(irq: u8) -> bool {
+    let (mut command, bit) = if irq < 8 {
+        (Port::<u8>::new(PIC_1_COMMAND), irq)
+    } else {
+        (Port::<u8>::new(PIC_2_COMMAND), irq - 8)
+    };
+    unsafe {
+        command.write(PIC_READ_ISR);
+        command.read() & (1 << bit) != 0
+    }
+}
+
+// Returns how many spurious IRQs the PICs raised so far.
+pub fn spurious_irq_count() -> u64 {
+    SPURIOUS_IRQS.load(Ordering::Relaxed)
+}
+
+// Returns how many interrupts arrived on the given vector without a handler being registered for
+// it.
+pub fn unhandled_interrupt_count(vector: u8) -> u64 {
+    UNHANDLED_INTERRUPTS[usize::from(vector)].load(Ordering::Relaxed)
+}
 
 lazy_static! {
     // this will only initialize the first time IDT is referenced
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
+        // every vector after the CPU exceptions gets a catch-all handler first, so an unexpected
+        // interrupt is reported instead of causing a double fault
+        for vector in 32..256 {
+            idt[vector].set_handler_fn(UNHANDLED_INTERRUPT_HANDLERS[vector / 16 - 2][vector % 16]);
+        }
+        idt[usize::from(PIC_1_SPURIOUS_VECTOR)].set_handler_fn(pic_1_spurious_interrupt_handler);
+        idt[usize::from(PIC_2_SPURIOUS_VECTOR)].set_handler_fn(pic_2_spurious_interrupt_handler);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
         idt[InterruptsIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptsIndex::Keyboard.as_usize()].set_handler_fn(keypress_interrupt_handler);
+        idt[InterruptsIndex::Rtc.as_usize()].set_handler_fn(rtc_interrupt_handler);
+        idt[InterruptsIndex::Mouse.as_usize()].set_handler_fn(mouse_interrupt_handler);
+        idt[InterruptsIndex::Hpet.as_usize()].set_handler_fn(hpet_interrupt_handler);
+        idt[InterruptsIndex::TlbShootdown.as_usize()]
+            .set_handler_fn(tlb_shootdown_interrupt_handler);
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(apic_spurious_interrupt_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
         unsafe {
             idt.double_fault.set_handler_fn(double_fault_handler)
@@ -93,43 +221,124 @@ extern "x86-interrupt" fn page_fault_handler(
     hlt_loop();
 }
 
+// The scancodes are only queued here, they are decoded by the keyboard task (see `task::keyboard`).
 extern "x86-interrupt" fn keypress_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
-
-    // creating a static Kebyboard reference
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
-            Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)
-        );
-    };
-    let mut keyboard = KEYBOARD.lock();
-
     // As soon as you press something, the keyboard controller will send the keypress data in the
     // 0x60 PS/2 port and then trigger the interrupt, now unless the data from the PS/2 port is
     // read, it will not send any more interrupts
     let mut port = Port::new(0x60);
     // we read the data from the port
     let scan_code: u8 = unsafe { port.read() };
-    // adding the scan_code to the task queue
-    add_scancode(scan_code);
-
-    // the PIC expects us to send an `end of interrupt (EOI)` signal from the handler
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptsIndex::Keyboard.as_u8());
+    // adding the scan_code to the task queue, unless it answers a command sent to the keyboard
+    if !ps2::handle_response(scan_code) {
+        add_scancode(scan_code);
     }
+
+    notify_end_of_interrupt(InterruptsIndex::Keyboard.as_u8());
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
-    // the PIC expects us to send an `end of interrupt (EOI)` signal from the handler
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptsIndex::Timer.as_u8());
+    // every CPU has its own local APIC timer, but only the bootstrap processor keeps the time and
+    // runs the threads
+    if !percpu::is_bsp() {
+        notify_end_of_interrupt(InterruptsIndex::Timer.as_u8());
+        return;
     }
+    time::tick();
+    wake_expired_timers();
+    notify_end_of_interrupt(InterruptsIndex::Timer.as_u8());
+    // might switch to another thread, so it has to come after the EOI
+    thread::preempt();
+}
+
+extern "x86-interrupt" fn rtc_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    rtc::handle_interrupt();
+    notify_end_of_interrupt(InterruptsIndex::Rtc.as_u8());
+}
+
+// Like the keyboard, the mouse sends its data through port 0x60, one byte per interrupt. The bytes
+// are assembled to packets by the mouse task (see `task::mouse`).
+extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let mut port = Port::new(0x60);
+    let byte: u8 = unsafe { port.read() };
+    add_mouse_byte(byte);
+
+    notify_end_of_interrupt(InterruptsIndex::Mouse.as_u8());
+}
+
+extern "x86-interrupt" fn hpet_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    wake_expired_timers();
+    notify_end_of_interrupt(InterruptsIndex::Hpet.as_u8());
 }
 
+extern "x86-interrupt" fn tlb_shootdown_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    smp::tlb::handle_shootdown();
+    notify_end_of_interrupt(InterruptsIndex::TlbShootdown.as_u8());
+}
+
+// the local APIC does not expect an EOI for a spurious interrupt
+extern "x86-interrupt" fn apic_spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+// IRQ 7 is spurious if it is not set in the In-Service Register of the primary PIC. In that case
+// the PIC does not expect an EOI, sending one could acknowledge a real interrupt by mistake.
+extern "x86-interrupt" fn pic_1_spurious_interrupt_handler(stack_frame: InterruptStackFrame) {
+    if apic::is_enabled() || pic_irq_in_service(7) {
+        report_unhandled_interrupt(PIC_1_SPURIOUS_VECTOR, &stack_frame);
+    } else {
+        SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// A spurious IRQ 15 comes from the secondary PIC, but the primary PIC saw a real interrupt on its
+// cascade line (IRQ 2). So only the primary PIC gets an EOI.
+extern "x86-interrupt" fn pic_2_spurious_interrupt_handler(stack_frame: InterruptStackFrame) {
+    if apic::is_enabled() || pic_irq_in_service(15) {
+        report_unhandled_interrupt(PIC_2_SPURIOUS_VECTOR, &stack_frame);
+    } else {
+        SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+        let _pics = PICS.lock();
+        unsafe { Port::<u8>::new(PIC_1_COMMAND).write(PIC_EOI) };
+    }
+}
+
+// Catch-all handler for the vectors that have no handler of their own. The handler function does
+// not learn which vector it was called for, so one copy is instantiated per vector through the
+// const generic parameter.
+extern "x86-interrupt" fn unhandled_interrupt<const VECTOR: u8>(stack_frame: InterruptStackFrame) {
+    report_unhandled_interrupt(VECTOR, &stack_frame);
+}
+
+fn report_unhandled_interrupt(vector: u8, stack_frame: &InterruptStackFrame) {
+    let count = UNHANDLED_INTERRUPTS[usize::from(vector)].fetch_add(1, Ordering::Relaxed) + 1;
+    // only log the first few occurrences, an interrupt storm would otherwise flood the screen
+    if count <= 3 {
+        println!(
+            "WARNING: unhandled interrupt {} (#{}) at {:?}",
+            vector, count, stack_frame.instruction_pointer
+        );
+    }
+    // the interrupt might come from an unmasked IRQ line without a driver, in which case the
+    // interrupt controller waits for an EOI
+    if (PIC_1_OFFSET..PIC_2_OFFSET + 8).contains(&vector) || apic::is_enabled() {
+        notify_end_of_interrupt(vector);
+    }
+}
+
+macro_rules! unhandled_interrupt_handlers {
+    ($($row:literal)*) => {
+        [$(unhandled_interrupt_handlers!(
+            @row $row, 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15
+        )),*]
+    };
+    (@row $row:literal, $($col:literal)*) => {
+        [$(unhandled_interrupt::<{ $row * 16 + $col }> as HandlerFunc),*]
+    };
+}
+
+// `unhandled_interrupt` instantiated for the vectors 32 to 255, in rows of 16
+static UNHANDLED_INTERRUPT_HANDLERS: [[HandlerFunc; 16]; 14] =
+    unhandled_interrupt_handlers!(2 3 4 5 6 7 8 9 10 11 12 13 14 15);
+
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
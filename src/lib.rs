@@ -11,11 +11,21 @@ fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
 
 extern crate alloc;
 
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod console;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
+pub mod percpu;
+pub mod ps2;
+pub mod rtc;
+pub mod smp;
+pub mod sync;
 pub mod task;
+pub mod thread;
+pub mod time;
 pub mod vga_buffer;
 
 pub fn hlt_loop() -> ! {
@@ -28,6 +38,7 @@ pub fn init() {
     interrupts::init_idt();
     gdt::init();
     unsafe { interrupts::PICS.lock().initialize() };
+    time::init();
     // the CPU by default does not listen to external hardware interrupts, we enable it to do so here
     x86_64::instructions::interrupts::enable();
 }
@@ -16,14 +16,11 @@ use core::panic::PanicInfo;
 
 use bootloader::{entry_point, BootInfo};
 use rust_os::{
-    allocator, hlt_loop,
+    acpi, allocator, apic, console, hlt_loop,
     memory::{self, BootInfoFrameAllocator},
-    println,
-    task::{
-        keyboard,
-        simple_executor::{self, SimpleExecutor},
-        Task,
-    },
+    percpu, print, println, ps2, smp,
+    task::{executor::Executor, Task},
+    thread, time,
 };
 use x86_64::VirtAddr;
 
@@ -46,12 +43,52 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    percpu::init(0);
+    // from now on, this function runs as the first kernel thread
+    thread::init();
 
-    let mut executor = SimpleExecutor::new();
-    executor.spawn(Task::new(keyboard::print_keypresses())); // new
+    // Switch from the legacy PICs to the APIC, if the machine has one
+    if let Err(err) = acpi::init() {
+        println!("WARNING: could not read the ACPI tables ({:?})", err);
+    }
+    if let Err(err) =
+        memory::with_kernel_memory(|mapper, frame_allocator| apic::init(mapper, frame_allocator))
+    {
+        println!(
+            "WARNING: APIC unavailable ({:?}); using the legacy PIC",
+            err
+        );
+    }
+    if let Err(err) = memory::with_kernel_memory(|mapper, frame_allocator| {
+        time::hpet::init(mapper, frame_allocator)
+    }) {
+        println!("WARNING: HPET unavailable ({:?})", err);
+    }
+    time::init_wall_clock();
+    if let Err(err) = ps2::init() {
+        println!("WARNING: PS/2 controller initialization failed ({:?})", err);
+    }
+    if let Err(err) = ps2::init_mouse() {
+        println!("WARNING: no PS/2 mouse ({:?})", err);
+    }
+    match memory::with_kernel_memory(|mapper, frame_allocator| smp::init(mapper, frame_allocator)) {
+        Ok(cpus) => println!("{} CPUs running", cpus),
+        Err(err) => println!("WARNING: could not start the other CPUs ({:?})", err),
+    }
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(echo_lines()));
     executor.run();
+}
 
-    hlt_loop();
+// Echoes every line typed on the keyboard.
+async fn echo_lines() {
+    loop {
+        print!("> ");
+        let line = console::read_line().await;
+        println!("{}", line);
+    }
 }
 
 // panic_handler, as the name suggests, is what knows how to handle a `panic`
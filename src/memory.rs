@@ -1,12 +1,36 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::Mutex;
 use x86_64::{
     registers::control::Cr3,
     structures::paging::{
-        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
+        mapper::{FlagUpdateError, MapToError, UnmapError},
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
+        Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
 
+use crate::smp::tlb;
+
+// Start of the virtual memory region where memory mapped device registers (local APIC, I/O APIC,
+// ...) are mapped. It is kept far away from the heap region.
+pub const MMIO_START: u64 = 0x_5555_5555_0000;
+// Start of the virtual memory region where the stacks that do not live on the heap (those of the
+// application processors and of the threads) are mapped.
+pub const STACKS_START: u64 = 0x_6666_6666_0000;
+
+// the offset at which the bootloader mapped the complete physical memory, set by `init`
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+// the next free virtual address in the MMIO region
+static MMIO_NEXT: AtomicU64 = AtomicU64::new(MMIO_START);
+// the next free virtual address in the stack region
+static STACKS_NEXT: AtomicU64 = AtomicU64::new(STACKS_START);
+// the kernel's page table and frame allocator once `kernel_main` handed them over with `install`
+static KERNEL_MEMORY: Mutex<Option<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> =
+    Mutex::new(None);
+
 // Frame allocator created from the memory map provided by the BootInfo struct from the
 // bootloader.
 pub struct BootInfoFrameAllocator {
@@ -56,10 +80,122 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
 // `physical_memory_offset`. Also, this function must be only called once
 // to avoid aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(phy_mem_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYS_MEM_OFFSET.store(phy_mem_offset.as_u64(), Ordering::Relaxed);
     let l4_pt = active_level4_page_table(phy_mem_offset);
     OffsetPageTable::new(l4_pt, phy_mem_offset)
 }
 
+// Hands the kernel's page table and frame allocator over to `with_kernel_memory`, so memory can
+// also be mapped by code that `kernel_main` does not call, e.g. `thread::spawn`.
+pub fn install(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    *KERNEL_MEMORY.lock() = Some((mapper, frame_allocator));
+}
+
+// Runs `f` with the kernel's page table and frame allocator. Must not be called from an interrupt
+// handler, or from within `f`.
+pub fn with_kernel_memory<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BootInfoFrameAllocator) -> R,
+) -> R {
+    let mut memory = KERNEL_MEMORY.lock();
+    let (mapper, frame_allocator) = memory.as_mut().expect("memory::install was not called");
+    f(mapper, frame_allocator)
+}
+
+// Returns the virtual address through which the given physical address can be accessed.
+//
+// This only works after `init` was called, since it relies on the bootloader mapping the complete
+// physical memory at `physical_memory_offset`.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
+// Maps `size` bytes of device registers starting at the physical address `phys` into the MMIO
+// region and returns the virtual address that corresponds to `phys`.
+//
+// Device registers must not be cached by the CPU (a read has to reach the device every time), so
+// the pages are mapped with the `NO_CACHE` and `WRITE_THROUGH` flags. We cannot simply go through
+// the physical memory mapping of the bootloader, since it only covers the regions listed in the
+// memory map and uses cacheable pages.
+//
+// This function is unsafe because the caller must guarantee that the given physical region
+// belongs to a device and is not used as normal memory.
+pub unsafe fn map_mmio(
+    phys: PhysAddr,
+    size: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let first_frame: PhysFrame = PhysFrame::containing_address(phys);
+    let last_frame = PhysFrame::containing_address(phys + (size - 1));
+    let frame_count = (last_frame.start_address() - first_frame.start_address()) / 4096 + 1;
+
+    let virt_start = VirtAddr::new(MMIO_NEXT.fetch_add(frame_count * 4096, Ordering::Relaxed));
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+
+    for (i, frame) in PhysFrame::range_inclusive(first_frame, last_frame).enumerate() {
+        let page: Page = Page::containing_address(virt_start + i * 4096);
+        mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+    }
+
+    Ok(virt_start + (phys - first_frame.start_address()))
+}
+
+// Maps a stack of `pages` pages backed by new frames and returns its end address, which is the
+// initial stack pointer since the stack grows downwards.
+//
+// The page below the stack is left unmapped as a guard page, so a stack overflow causes a page
+// fault instead of silently overwriting whatever lies below.
+pub fn map_stack(
+    pages: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let guard_page: Page = Page::containing_address(VirtAddr::new(
+        STACKS_NEXT.fetch_add((pages + 1) * 4096, Ordering::Relaxed),
+    ));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for page in Page::range(guard_page + 1, guard_page + 1 + pages) {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+    Ok((guard_page + 1 + pages).start_address())
+}
+
+// Removes the mapping of `page` and returns the frame it was mapped to. The TLB entries of the
+// page are invalidated on all CPUs before returning, so the frame can be reused right away.
+//
+// Must be called with interrupts enabled, see `smp::tlb::shootdown`.
+pub fn unmap(
+    page: Page<Size4KiB>,
+    mapper: &mut impl Mapper<Size4KiB>,
+) -> Result<PhysFrame<Size4KiB>, UnmapError> {
+    let (frame, flush) = mapper.unmap(page)?;
+    // the shootdown flushes the current CPU as well
+    flush.ignore();
+    tlb::shootdown_page(page);
+    Ok(frame)
+}
+
+// Changes the flags of the mapping of `page`, invalidating its TLB entries on all CPUs.
+//
+// This function is unsafe because the caller must guarantee that the new flags do not break
+// memory safety, e.g. by making memory that is still in use inaccessible. Must be called with
+// interrupts enabled, see `smp::tlb::shootdown`.
+pub unsafe fn update_flags(
+    page: Page<Size4KiB>,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<Size4KiB>,
+) -> Result<(), FlagUpdateError> {
+    mapper.update_flags(page, flags)?.ignore();
+    tlb::shootdown_page(page);
+    Ok(())
+}
+
 //. Returns a mutable reference to the active level 4 page table
 //
 // This function is unsafe because the caller must guarantee that the physical memory is mapped to
@@ -0,0 +1,73 @@
+// Data that every CPU has its own copy of. The GS base register of each CPU points to its
+// `PerCpu`, so the current CPU's data can be found with a single `gs`-relative load, without
+// knowing which CPU we are running on.
+
+use alloc::boxed::Box;
+use core::arch::{asm, x86_64::__cpuid};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use x86_64::registers::model_specific::GsBase;
+use x86_64::VirtAddr;
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+#[repr(C)]
+pub struct PerCpu {
+    // must be the first field, `current` loads it from `gs:0`
+    this: *const PerCpu,
+    // index of the CPU, the bootstrap processor (BSP) is 0. The indices are unique but can have
+    // gaps, see `smp::init`.
+    pub id: usize,
+    pub apic_id: u32,
+    // id of the task the executor of this CPU is polling, `u64::MAX` if none, see
+    // `executor::current_task`
+    pub(crate) current_task: AtomicU64,
+    // poll budget of that task, see `task::budget`
+    pub(crate) budget: AtomicU32,
+}
+
+// Allocates the per-CPU data of the current CPU and points its GS base at it. Must be called once
+// on every CPU, after the heap was initialized.
+pub fn init(id: usize) {
+    // CPUID leaf 1 reports the initial local APIC id in the highest byte of EBX
+    let apic_id = unsafe { __cpuid(1) }.ebx >> 24;
+    let per_cpu = Box::leak(Box::new(PerCpu {
+        this: core::ptr::null(),
+        id,
+        apic_id,
+        current_task: AtomicU64::new(u64::MAX),
+        budget: AtomicU32::new(crate::task::budget::POLL_BUDGET),
+    }));
+    per_cpu.this = per_cpu;
+    GsBase::write(VirtAddr::from_ptr(per_cpu as *const PerCpu));
+    if id == 0 {
+        INITIALIZED.store(true, Ordering::Release);
+    } else {
+        CPU_COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Returns the data of the current CPU, or `None` before `init` ran on the BSP.
+pub fn current() -> Option<&'static PerCpu> {
+    if !INITIALIZED.load(Ordering::Acquire) {
+        return None;
+    }
+    let this: *const PerCpu;
+    unsafe { asm!("mov {}, gs:[0]", out(reg) this, options(nostack, preserves_flags, readonly)) };
+    Some(unsafe { &*this })
+}
+
+// Returns the index of the current CPU.
+pub fn id() -> usize {
+    current().map_or(0, |per_cpu| per_cpu.id)
+}
+
+// Returns whether this is the bootstrap processor, the one that booted the kernel.
+pub fn is_bsp() -> bool {
+    id() == 0
+}
+
+// Returns the number of CPUs that were started.
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Relaxed)
+}
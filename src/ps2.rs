@@ -0,0 +1,557 @@
+// Driver for the PS/2 controller (the Intel 8042 or whatever emulates it), which connects the
+// keyboard and the mouse. `init` resets the controller and the keyboard into a known state instead
+// of relying on what the firmware left behind; afterwards the keyboard interrupt handler reads the
+// scancodes, and commands sent to the keyboard at runtime (e.g. to update the LEDs) get their
+// answers through that handler.
+
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::interrupts::{set_irq_masked, InterruptsIndex};
+use crate::time::{self, pit};
+
+const DATA_PORT: u16 = 0x60;
+// reading it returns the status register, writing it sends a command to the controller
+const COMMAND_PORT: u16 = 0x64;
+
+// bits of the status register
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+// controller commands
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_SECOND_PORT: u8 = 0xa7;
+const ENABLE_SECOND_PORT: u8 = 0xa8;
+const TEST_SECOND_PORT: u8 = 0xa9;
+const SELF_TEST: u8 = 0xaa;
+const TEST_FIRST_PORT: u8 = 0xab;
+const DISABLE_FIRST_PORT: u8 = 0xad;
+const ENABLE_FIRST_PORT: u8 = 0xae;
+// the next byte written to the data port goes to the device on the second port
+const WRITE_SECOND_PORT: u8 = 0xd4;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// bits of the controller configuration byte
+const CONFIG_FIRST_PORT_INTERRUPT: u8 = 1 << 0;
+const CONFIG_SECOND_PORT_INTERRUPT: u8 = 1 << 1;
+const CONFIG_SECOND_PORT_CLOCK_DISABLED: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+// keyboard commands
+const SET_LEDS: u8 = 0xed;
+const SCANCODE_SET: u8 = 0xf0;
+const SET_TYPEMATIC: u8 = 0xf3;
+const ENABLE_SCANNING: u8 = 0xf4;
+const DISABLE_SCANNING: u8 = 0xf5;
+const RESET: u8 = 0xff;
+
+// mouse commands, the ones shared with the keyboard (e.g. `RESET`) are above
+const GET_DEVICE_ID: u8 = 0xf2;
+const SET_SAMPLE_RATE: u8 = 0xf3;
+const ENABLE_DATA_REPORTING: u8 = 0xf4;
+const SET_DEFAULTS: u8 = 0xf6;
+
+// device ids of mice with a scroll wheel, which send 4 byte packets
+const INTELLIMOUSE_ID: u8 = 3;
+const INTELLIMOUSE_EXPLORER_ID: u8 = 4;
+
+// answers of the devices
+const ACK: u8 = 0xfa;
+const RESEND: u8 = 0xfe;
+const RESET_PASSED: u8 = 0xaa;
+
+// how often a command is repeated when the device asks for it
+const MAX_RESENDS: usize = 3;
+// the status register is polled every `POLL_INTERVAL_US` for at most `TIMEOUT_US`
+const POLL_INTERVAL_US: u32 = 50;
+const TIMEOUT_US: u32 = 100_000;
+// a keyboard takes a lot longer to run its self-test after a reset
+const RESET_TIMEOUT_US: u32 = 1_000_000;
+
+// no answer was received yet, see `RESPONSE`
+const NO_RESPONSE: u16 = u16::MAX;
+// the configuration was not read from the controller yet, see `CONFIG`
+const NO_CONFIG: u16 = u16::MAX;
+
+// states of the LED update started by `update_leds` and finished by the interrupt handler
+const LEDS_IDLE: u8 = 0;
+const LEDS_COMMAND_SENT: u8 = 1;
+const LEDS_VALUE_SENT: u8 = 2;
+
+// Serializes the commands sent to the controller and the devices.
+static CONTROLLER: Mutex<()> = Mutex::new(());
+// set once `init` enabled the interrupts of the keyboard, from then on the answers of the keyboard
+// arrive through `handle_response` instead of being polled
+static INTERRUPT_DRIVEN: AtomicBool = AtomicBool::new(false);
+static RESPONSE_EXPECTED: AtomicBool = AtomicBool::new(false);
+static RESPONSE: AtomicU16 = AtomicU16::new(NO_RESPONSE);
+// the configuration byte last read from or written to the controller
+static CONFIG: AtomicU16 = AtomicU16::new(NO_CONFIG);
+static HAS_SECOND_PORT: AtomicBool = AtomicBool::new(false);
+// whether the controller really translates, the firmware leaves translation enabled
+static TRANSLATION: AtomicBool = AtomicBool::new(true);
+static MOUSE_HAS_WHEEL: AtomicBool = AtomicBool::new(false);
+
+static LEDS_STATE: AtomicU8 = AtomicU8::new(LEDS_IDLE);
+// the LEDs asked for last, the ones being sent and the ones the keyboard acknowledged
+static LEDS_WANTED: AtomicU8 = AtomicU8::new(0);
+static LEDS_SENDING: AtomicU8 = AtomicU8::new(0);
+static LEDS_CURRENT: AtomicU8 = AtomicU8::new(0);
+static LEDS_RESENDS: AtomicUsize = AtomicUsize::new(0);
+// uptime in nanoseconds when the LED update in progress was started
+static LEDS_STARTED: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    // the controller or the device did not answer in time
+    Timeout,
+    // the controller self-test returned this instead of 0x55
+    SelfTestFailed(u8),
+    // the interface test of a port returned this error code
+    PortTestFailed(u8),
+    // the controller has no second port, so there is no mouse
+    NoSecondPort,
+    // the device kept asking for the command to be resent
+    TooManyResends,
+    UnexpectedResponse(u8),
+}
+
+// The keyboard LEDs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Leds {
+    pub scroll_lock: bool,
+    pub num_lock: bool,
+    pub caps_lock: bool,
+}
+
+impl Leds {
+    fn as_u8(self) -> u8 {
+        u8::from(self.scroll_lock) | u8::from(self.num_lock) << 1 | u8::from(self.caps_lock) << 2
+    }
+}
+
+// Initializes the controller and the keyboard: runs the self-tests, switches the keyboard to
+// scancode set 2 (which the controller usually translates to set 1, see `translation_enabled`), sets the
+// default typematic rate and resets the LEDs.
+//
+// The keyboard port and its interrupt are enabled again even if an error is returned, so the
+// keyboard keeps working with the firmware's settings as far as possible.
+pub fn init() -> Result<(), Ps2Error> {
+    let _controller = CONTROLLER.lock();
+    // the interrupts of the ports stay disabled until the end, so the answers of the keyboard are
+    // polled instead of ending up in the interrupt handler
+    let result = init_controller();
+    let enabled = controller_command(ENABLE_FIRST_PORT);
+    let result = result.and(enabled).and_then(|()| init_keyboard());
+    // only a configuration read from the controller is written back, a made up one could turn off
+    // the translation. Without one, the interrupts were never disabled.
+    let interrupt_enabled = match CONFIG.load(Ordering::Relaxed) {
+        NO_CONFIG => Ok(()),
+        config => write_config(config as u8 | CONFIG_FIRST_PORT_INTERRUPT),
+    };
+    INTERRUPT_DRIVEN.store(true, Ordering::Release);
+    result.and(interrupt_enabled)
+}
+
+fn init_controller() -> Result<(), Ps2Error> {
+    // the devices must not send anything while the controller is reconfigured
+    controller_command(DISABLE_FIRST_PORT)?;
+    controller_command(DISABLE_SECOND_PORT)?;
+    flush_output();
+
+    let mut config = read_config()?;
+    config &= !(CONFIG_FIRST_PORT_INTERRUPT | CONFIG_SECOND_PORT_INTERRUPT);
+    config |= CONFIG_TRANSLATION;
+    write_config(config)?;
+
+    controller_command(SELF_TEST)?;
+    match read_data(TIMEOUT_US)? {
+        SELF_TEST_PASSED => {}
+        result => return Err(Ps2Error::SelfTestFailed(result)),
+    }
+    // some controllers reset themselves during the self-test
+    write_config(config)?;
+
+    // the clock of the second port is only enabled by the enable command if it exists
+    controller_command(ENABLE_SECOND_PORT)?;
+    let has_second_port = read_config()? & CONFIG_SECOND_PORT_CLOCK_DISABLED == 0;
+    HAS_SECOND_PORT.store(has_second_port, Ordering::Relaxed);
+    controller_command(DISABLE_SECOND_PORT)?;
+    write_config(config)?;
+    // some controllers cannot translate and ignore the bit, the keyboard then sends set 2 as is
+    let translation = read_config()? & CONFIG_TRANSLATION != 0;
+    TRANSLATION.store(translation, Ordering::Relaxed);
+
+    controller_command(TEST_FIRST_PORT)?;
+    match read_data(TIMEOUT_US)? {
+        PORT_TEST_PASSED => Ok(()),
+        result => Err(Ps2Error::PortTestFailed(result)),
+    }
+}
+
+fn init_keyboard() -> Result<(), Ps2Error> {
+    keyboard_command(DISABLE_SCANNING)?;
+    keyboard_command(RESET)?;
+    match read_data(RESET_TIMEOUT_US)? {
+        RESET_PASSED => {}
+        response => return Err(Ps2Error::UnexpectedResponse(response)),
+    }
+    keyboard_command(SCANCODE_SET)?;
+    keyboard_command(2)?;
+    send_typematic(500, 10)?;
+    // Num Lock starts out on, like in `task::keyboard`
+    send_leds(Leds {
+        num_lock: true,
+        ..Leds::default()
+    })?;
+    keyboard_command(ENABLE_SCANNING)
+}
+
+// Enables the second port and initializes the mouse connected to it, switching it to 4 byte
+// packets if it has a scroll wheel. Its interrupt (IRQ 12) is unmasked afterwards, the packets are
+// decoded by `task::mouse`. Must be called after `init`.
+pub fn init_mouse() -> Result<(), Ps2Error> {
+    if !has_second_port() {
+        return Err(Ps2Error::NoSecondPort);
+    }
+    let _controller = CONTROLLER.lock();
+    // keystrokes must not be mistaken for answers of the mouse while they are polled
+    controller_command(DISABLE_FIRST_PORT)?;
+    // enabling the second port cleared its clock disabled bit, so the configuration is read again
+    let result = init_mouse_device()
+        .and_then(|()| read_config())
+        .and_then(|config| write_config(config | CONFIG_SECOND_PORT_INTERRUPT));
+    controller_command(ENABLE_FIRST_PORT)?;
+    result?;
+    set_irq_masked(InterruptsIndex::Mouse.as_irq(), false);
+    Ok(())
+}
+
+fn init_mouse_device() -> Result<(), Ps2Error> {
+    controller_command(TEST_SECOND_PORT)?;
+    match read_data(TIMEOUT_US)? {
+        PORT_TEST_PASSED => {}
+        result => return Err(Ps2Error::PortTestFailed(result)),
+    }
+    controller_command(ENABLE_SECOND_PORT)?;
+
+    mouse_command(RESET)?;
+    match read_data(RESET_TIMEOUT_US)? {
+        RESET_PASSED => {}
+        response => return Err(Ps2Error::UnexpectedResponse(response)),
+    }
+    // the reset is followed by the device id
+    read_data(TIMEOUT_US)?;
+    mouse_command(SET_DEFAULTS)?;
+
+    // this sequence of sample rates is the secret knock that enables the scroll wheel of an
+    // IntelliMouse, which then reports a different device id
+    for rate in [200, 100, 80] {
+        mouse_command(SET_SAMPLE_RATE)?;
+        mouse_command(rate)?;
+    }
+    mouse_command(GET_DEVICE_ID)?;
+    let id = read_data(TIMEOUT_US)?;
+    MOUSE_HAS_WHEEL.store(
+        id == INTELLIMOUSE_ID || id == INTELLIMOUSE_EXPLORER_ID,
+        Ordering::Relaxed,
+    );
+    mouse_command(SET_SAMPLE_RATE)?;
+    mouse_command(100)?;
+    mouse_command(ENABLE_DATA_REPORTING)
+}
+
+// Returns whether the mouse has a scroll wheel and therefore sends 4 byte packets.
+pub fn mouse_has_wheel() -> bool {
+    MOUSE_HAS_WHEEL.load(Ordering::Relaxed)
+}
+
+// Returns whether the controller translates the scancodes of the keyboard to scancode set 1,
+// otherwise `task::keyboard` decodes set 2.
+pub fn translation_enabled() -> bool {
+    TRANSLATION.load(Ordering::Relaxed)
+}
+
+// Returns whether the controller has a second (mouse) port.
+pub fn has_second_port() -> bool {
+    HAS_SECOND_PORT.load(Ordering::Relaxed)
+}
+
+// Turns the keyboard LEDs on or off and waits until the keyboard acknowledged it.
+pub fn set_leds(leds: Leds) -> Result<(), Ps2Error> {
+    let result = {
+        let _controller = CONTROLLER.lock();
+        send_leds(leds)
+    };
+    start_leds_update();
+    result
+}
+
+// Turns the keyboard LEDs on or off without waiting for the keyboard: the command is sent right
+// away (or once the command in progress is done) and finished by the keyboard interrupt handler.
+// Errors are not reported, the next update tries again.
+pub fn update_leds(leds: Leds) {
+    LEDS_WANTED.store(leds.as_u8(), Ordering::Release);
+    start_leds_update();
+}
+
+// Starts sending `LEDS_WANTED` to the keyboard, unless the keyboard already shows them or an update
+// is in progress (the interrupt handler starts the next one when it is done).
+fn start_leds_update() {
+    if LEDS_WANTED.load(Ordering::Acquire) == LEDS_CURRENT.load(Ordering::Acquire) {
+        return;
+    }
+    // a command sent by `keyboard_command` at the same time would mix up the answers, so the state
+    // only changes while holding the lock. Whoever holds it starts the update once it is released.
+    let _controller = match CONTROLLER.try_lock() {
+        Some(controller) => controller,
+        None => return,
+    };
+    let now = time::now().as_uptime_nanos();
+    let state = LEDS_STATE.load(Ordering::Acquire);
+    // an update the keyboard never answered is given up after a while
+    let timed_out =
+        now.saturating_sub(LEDS_STARTED.load(Ordering::Relaxed)) > u64::from(TIMEOUT_US) * 1000;
+    if state != LEDS_IDLE && !timed_out {
+        return;
+    }
+    if LEDS_STATE
+        .compare_exchange(
+            state,
+            LEDS_COMMAND_SENT,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        )
+        .is_err()
+    {
+        return;
+    }
+    LEDS_STARTED.store(now, Ordering::Relaxed);
+    LEDS_RESENDS.store(0, Ordering::Relaxed);
+    write_data_now(SET_LEDS);
+}
+
+// Called by the keyboard interrupt handler with the answer of the keyboard to a byte of the LED
+// update
+//
+// Must not block or allocate
+fn continue_leds_update(state: u8, response: u8) {
+    if response == RESEND {
+        if LEDS_RESENDS.fetch_add(1, Ordering::Relaxed) + 1 >= MAX_RESENDS {
+            LEDS_STATE.store(LEDS_IDLE, Ordering::Release);
+        } else if state == LEDS_COMMAND_SENT {
+            write_data_now(SET_LEDS);
+        } else {
+            write_data_now(LEDS_SENDING.load(Ordering::Relaxed));
+        }
+        return;
+    }
+    if state == LEDS_COMMAND_SENT {
+        let leds = LEDS_WANTED.load(Ordering::Acquire);
+        LEDS_SENDING.store(leds, Ordering::Relaxed);
+        LEDS_RESENDS.store(0, Ordering::Relaxed);
+        LEDS_STATE.store(LEDS_VALUE_SENT, Ordering::Release);
+        write_data_now(leds);
+    } else {
+        LEDS_CURRENT.store(LEDS_SENDING.load(Ordering::Relaxed), Ordering::Release);
+        LEDS_STATE.store(LEDS_IDLE, Ordering::Release);
+        // the LEDs might have changed again in the meantime
+        start_leds_update();
+    }
+}
+
+// Waits until the keyboard answered the LED update in progress, so its answers are not taken for
+// those of the next command.
+fn wait_for_leds_update() {
+    for _ in 0..TIMEOUT_US / POLL_INTERVAL_US {
+        if LEDS_STATE.load(Ordering::Acquire) == LEDS_IDLE {
+            return;
+        }
+        pit::busy_wait_us(POLL_INTERVAL_US);
+    }
+    // the keyboard never answered
+    LEDS_STATE.store(LEDS_IDLE, Ordering::Release);
+}
+
+// Sets how long a key has to be held before it repeats (250ms to 1s, rounded to steps of 250ms)
+// and how often it repeats then (2 to 30 times per second, rounded to the nearest supported
+// rate).
+pub fn set_typematic(delay_ms: u32, repeats_per_second: u32) -> Result<(), Ps2Error> {
+    let result = {
+        let _controller = CONTROLLER.lock();
+        send_typematic(delay_ms, repeats_per_second)
+    };
+    // an LED update might have waited for the lock
+    start_leds_update();
+    result
+}
+
+fn send_leds(leds: Leds) -> Result<(), Ps2Error> {
+    LEDS_WANTED.store(leds.as_u8(), Ordering::Release);
+    keyboard_command(SET_LEDS)?;
+    keyboard_command(leds.as_u8())?;
+    LEDS_CURRENT.store(leds.as_u8(), Ordering::Release);
+    Ok(())
+}
+
+fn send_typematic(delay_ms: u32, repeats_per_second: u32) -> Result<(), Ps2Error> {
+    let delay = ((delay_ms + 125) / 250).clamp(1, 4) - 1;
+    // the repeat period of a rate value is (8 + A) * 2^B * 4.17ms, with A in bits 0-2 and B in
+    // bits 3-4. 0 is the fastest rate, 31 the slowest.
+    let target_period_us = 1_000_000 / repeats_per_second.clamp(2, 30);
+    let rate = (0..32u32)
+        .min_by_key(|rate| {
+            let period_us = (8 + (rate & 7)) * (1 << ((rate >> 3) & 3)) * 4170;
+            period_us.abs_diff(target_period_us)
+        })
+        .unwrap_or(0);
+    keyboard_command(SET_TYPEMATIC)?;
+    keyboard_command((delay << 5 | rate) as u8)
+}
+
+// Called by the keyboard interrupt handler with the byte it read from the data port, returns
+// whether it was the answer to a command sent to the keyboard (and therefore not a scancode)
+//
+// Must not block or allocate
+pub(crate) fn handle_response(byte: u8) -> bool {
+    // 0xfa and 0xfe are not valid scancodes, so a key pressed while waiting for an answer cannot
+    // be mistaken for it
+    if byte != ACK && byte != RESEND {
+        return false;
+    }
+    let leds_state = LEDS_STATE.load(Ordering::Acquire);
+    if leds_state != LEDS_IDLE {
+        continue_leds_update(leds_state, byte);
+        return true;
+    }
+    if RESPONSE_EXPECTED.swap(false, Ordering::AcqRel) {
+        RESPONSE.store(u16::from(byte), Ordering::Release);
+        return true;
+    }
+    false
+}
+
+// Sends a command byte to the keyboard, repeating it until it is acknowledged.
+fn keyboard_command(byte: u8) -> Result<(), Ps2Error> {
+    wait_for_leds_update();
+    for _ in 0..MAX_RESENDS {
+        let interrupt_driven =
+            INTERRUPT_DRIVEN.load(Ordering::Acquire) && interrupts::are_enabled();
+        if interrupt_driven {
+            RESPONSE.store(NO_RESPONSE, Ordering::Relaxed);
+            RESPONSE_EXPECTED.store(true, Ordering::Release);
+        }
+        write_data(byte)?;
+        let response = if interrupt_driven {
+            wait_for_interrupt_response()
+        } else {
+            read_data(TIMEOUT_US)
+        };
+        match response? {
+            ACK => return Ok(()),
+            RESEND => continue,
+            response => return Err(Ps2Error::UnexpectedResponse(response)),
+        }
+    }
+    Err(Ps2Error::TooManyResends)
+}
+
+// Sends a command byte to the mouse, repeating it until it is acknowledged. The answers are always
+// polled, the mouse is only sent commands by `init_mouse`.
+fn mouse_command(byte: u8) -> Result<(), Ps2Error> {
+    for _ in 0..MAX_RESENDS {
+        controller_command(WRITE_SECOND_PORT)?;
+        write_data(byte)?;
+        match read_data(TIMEOUT_US)? {
+            ACK => return Ok(()),
+            RESEND => continue,
+            response => return Err(Ps2Error::UnexpectedResponse(response)),
+        }
+    }
+    Err(Ps2Error::TooManyResends)
+}
+
+fn wait_for_interrupt_response() -> Result<u8, Ps2Error> {
+    for _ in 0..TIMEOUT_US / POLL_INTERVAL_US {
+        match RESPONSE.load(Ordering::Acquire) {
+            NO_RESPONSE => pit::busy_wait_us(POLL_INTERVAL_US),
+            response => return Ok(response as u8),
+        }
+    }
+    RESPONSE_EXPECTED.store(false, Ordering::Release);
+    Err(Ps2Error::Timeout)
+}
+
+fn controller_command(command: u8) -> Result<(), Ps2Error> {
+    wait_for_status(STATUS_INPUT_FULL, false, TIMEOUT_US)?;
+    unsafe { Port::<u8>::new(COMMAND_PORT).write(command) };
+    Ok(())
+}
+
+fn read_config() -> Result<u8, Ps2Error> {
+    controller_command(READ_CONFIG)?;
+    let config = read_data(TIMEOUT_US)?;
+    CONFIG.store(u16::from(config), Ordering::Relaxed);
+    Ok(config)
+}
+
+fn write_config(config: u8) -> Result<(), Ps2Error> {
+    controller_command(WRITE_CONFIG)?;
+    write_data(config)?;
+    CONFIG.store(u16::from(config), Ordering::Relaxed);
+    Ok(())
+}
+
+fn write_data(byte: u8) -> Result<(), Ps2Error> {
+    wait_for_status(STATUS_INPUT_FULL, false, TIMEOUT_US)?;
+    unsafe { Port::<u8>::new(DATA_PORT).write(byte) };
+    Ok(())
+}
+
+// Like `write_data`, but without the timed wait, so it can be used by interrupt handlers. The
+// controller empties its input buffer within microseconds, so this only spins briefly.
+fn write_data_now(byte: u8) {
+    let mut status = Port::<u8>::new(COMMAND_PORT);
+    for _ in 0..10_000 {
+        if unsafe { status.read() } & STATUS_INPUT_FULL == 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    unsafe { Port::<u8>::new(DATA_PORT).write(byte) };
+}
+
+fn read_data(timeout_us: u32) -> Result<u8, Ps2Error> {
+    wait_for_status(STATUS_OUTPUT_FULL, true, timeout_us)?;
+    Ok(unsafe { Port::<u8>::new(DATA_PORT).read() })
+}
+
+// Discards whatever the devices sent before they were disabled.
+fn flush_output() {
+    let mut status = Port::<u8>::new(COMMAND_PORT);
+    let mut data = Port::<u8>::new(DATA_PORT);
+    // bounded, a broken controller might report a full buffer forever
+    for _ in 0..64 {
+        if unsafe { status.read() } & STATUS_OUTPUT_FULL == 0 {
+            break;
+        }
+        unsafe { data.read() };
+    }
+}
+
+fn wait_for_status(bit: u8, set: bool, timeout_us: u32) -> Result<(), Ps2Error> {
+    let mut status = Port::<u8>::new(COMMAND_PORT);
+    for _ in 0..timeout_us / POLL_INTERVAL_US {
+        if (unsafe { status.read() } & bit != 0) == set {
+            return Ok(());
+        }
+        pit::busy_wait_us(POLL_INTERVAL_US);
+    }
+    Err(Ps2Error::Timeout)
+}
@@ -0,0 +1,288 @@
+// Driver for the real-time clock (RTC) of the CMOS chip, which keeps the calendar date and time
+// while the machine is powered off. It is only read once at boot, afterwards the wall clock is
+// advanced with the monotonic tick counter (see `time::wall_clock`).
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+use x86_64::instructions::port::Port;
+
+use crate::acpi;
+use crate::interrupts::{set_irq_masked, InterruptsIndex};
+use crate::sync::IrqSafeMutex;
+
+// CMOS registers of the RTC
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY_OF_MONTH: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+
+// bit 7 of status register A is set while the RTC updates its registers
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+// bits of status register B
+const PERIODIC_INTERRUPT_ENABLE: u8 = 1 << 6;
+const BINARY_MODE: u8 = 1 << 2;
+const HOUR_24_MODE: u8 = 1 << 1;
+// in 12 hour mode the highest bit of the hours register marks PM
+const HOUR_PM: u8 = 1 << 7;
+// setting bit 7 of the register index disables NMIs while the register is accessed, the bit is
+// cleared again afterwards
+const NMI_DISABLE: u8 = 1 << 7;
+
+// index of the century register, 0 if there is none. Taken from the FADT if it has one.
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+// The CMOS is accessed through an index port and a data port, so selecting a register and
+// accessing it must not be interrupted by another access. The interrupt handler has to take the
+// lock too, so it is only held with interrupts disabled.
+static CMOS: IrqSafeMutex<Cmos> = IrqSafeMutex::new(Cmos {
+    index: Port::new(0x70),
+    data: Port::new(0x71),
+});
+
+struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    fn read(&mut self, register: u8) -> u8 {
+        unsafe {
+            self.index.write(NMI_DISABLE | register);
+            let value = self.data.read();
+            self.index.write(register);
+            value
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.index.write(NMI_DISABLE | register);
+            self.data.write(value);
+            self.index.write(register);
+        }
+    }
+
+    // Reads the raw date and time registers, without interpreting the RTC modes.
+    fn read_raw(&mut self, century_register: u8) -> [u8; 7] {
+        while self.read(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+            core::hint::spin_loop();
+        }
+        let century = if century_register != 0 {
+            self.read(century_register)
+        } else {
+            0
+        };
+        [
+            self.read(SECONDS),
+            self.read(MINUTES),
+            self.read(HOURS),
+            self.read(DAY_OF_MONTH),
+            self.read(MONTH),
+            self.read(YEAR),
+            century,
+        ]
+    }
+}
+
+// Returns the index of the CMOS century register from the FADT, or 0 if it does not specify one.
+fn century_register_from_fadt() -> u8 {
+    // the century field is at offset 108 of the FADT
+    match acpi::find_table(b"FACP") {
+        Ok(fadt) => {
+            let header = unsafe { acpi::read_phys::<acpi::SdtHeader>(fadt) };
+            if header.length > 108 {
+                unsafe { acpi::read_phys::<u8>(fadt + 108u64) }
+            } else {
+                0
+            }
+        }
+        Err(_) => 0,
+    }
+}
+
+// Looks up the century register and returns the current date and time. Must be called after
+// `acpi::init`, otherwise the 21st century is assumed.
+pub fn init() -> DateTime {
+    CENTURY_REGISTER.store(century_register_from_fadt(), Ordering::Relaxed);
+    read_date_time()
+}
+
+// Reads the current date and time from the RTC.
+//
+// This waits for the RTC to finish updating, and reads all registers until two reads in a row
+// return the same values, so we never see a half updated time (e.g. 12:59:00 instead of
+// 13:00:00).
+pub fn read_date_time() -> DateTime {
+    let century_register = CENTURY_REGISTER.load(Ordering::Relaxed);
+    let (raw, status_b) = {
+        let mut cmos = CMOS.lock();
+        let mut raw = cmos.read_raw(century_register);
+        loop {
+            let again = cmos.read_raw(century_register);
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, cmos.read(STATUS_B))
+    };
+
+    let [second, minute, hour, day, month, year, century] = raw;
+    let binary = status_b & BINARY_MODE != 0;
+    let decode = |value: u8| {
+        if binary {
+            value
+        } else {
+            bcd_to_binary(value)
+        }
+    };
+
+    let mut hour_value = decode(hour & !HOUR_PM);
+    if status_b & HOUR_24_MODE == 0 {
+        // 12 hour mode: 12 AM is midnight, 12 PM is noon
+        hour_value %= 12;
+        if hour & HOUR_PM != 0 {
+            hour_value += 12;
+        }
+    }
+    let century = if century_register != 0 {
+        decode(century) as u16
+    } else {
+        20
+    };
+
+    DateTime {
+        year: century * 100 + decode(year) as u16,
+        month: decode(month),
+        day: decode(day),
+        hour: hour_value,
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0f) + (value >> 4) * 10
+}
+
+// Enables the periodic interrupt of the RTC on IRQ 8, firing at `32768 >> (rate - 1)` Hz. `rate`
+// must be between 3 (8192 Hz) and 15 (2 Hz).
+pub fn enable_periodic_interrupt(rate: u8) {
+    assert!((3..=15).contains(&rate), "invalid RTC rate {}", rate);
+    {
+        let mut cmos = CMOS.lock();
+        let status_a = cmos.read(STATUS_A);
+        cmos.write(STATUS_A, (status_a & 0xf0) | rate);
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status_b | PERIODIC_INTERRUPT_ENABLE);
+        // a pending interrupt has to be acknowledged before the RTC raises the next one
+        cmos.read(STATUS_C);
+    }
+    set_irq_masked(InterruptsIndex::Rtc.as_irq(), false);
+}
+
+pub fn disable_periodic_interrupt() {
+    set_irq_masked(InterruptsIndex::Rtc.as_irq(), true);
+    let mut cmos = CMOS.lock();
+    let status_b = cmos.read(STATUS_B);
+    cmos.write(STATUS_B, status_b & !PERIODIC_INTERRUPT_ENABLE);
+}
+
+// Returns how many periodic interrupts the RTC raised.
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+// Called by the RTC interrupt handler
+//
+// Must not block or allocate
+pub(crate) fn handle_interrupt() {
+    // the RTC does not raise another interrupt until status register C was read. Whoever holds
+    // the lock runs on another CPU (it is only held with interrupts disabled) and releases it soon.
+    CMOS.lock().read(STATUS_C);
+    PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+// A calendar date and time in UTC (assuming the RTC is set to UTC).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    // Converts a UNIX timestamp (seconds since 1970-01-01 00:00:00 UTC) into a date and time.
+    pub fn from_unix_timestamp(timestamp: u64) -> DateTime {
+        let days = (timestamp / 86400) as i64;
+        let seconds_of_day = timestamp % 86400;
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year: year as u16,
+            month,
+            day,
+            hour: (seconds_of_day / 3600) as u8,
+            minute: (seconds_of_day / 60 % 60) as u8,
+            second: (seconds_of_day % 60) as u8,
+        }
+    }
+
+    // Returns the number of seconds since 1970-01-01 00:00:00 UTC, or 0 for earlier dates.
+    pub fn unix_timestamp(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month, self.day);
+        let seconds =
+            days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        u64::try_from(seconds).unwrap_or(0)
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// Returns the number of days between 1970-01-01 and the given date of the proleptic Gregorian
+// calendar. See http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (month as i64 + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// The inverse of `days_from_civil`, returns (year, month, day).
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u8;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
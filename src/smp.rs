@@ -0,0 +1,213 @@
+// Starts the application processors (APs), i.e. all CPU cores except the bootstrap processor
+// (BSP) that runs `kernel_main`. Every AP gets its own GDT, TSS, per-CPU data and stack, and runs
+// its own executor, which steals work from the others when it has none.
+
+pub mod tlb;
+mod trampoline;
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, PageTableFlags, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+use crate::apic::{self, lapic};
+use crate::task::executor::Executor;
+use crate::time::pit;
+use crate::{acpi, gdt, interrupts, memory, percpu, println};
+
+// size of the stack of every AP, in pages
+const AP_STACK_PAGES: u64 = 16;
+
+// set by an AP once it is done with the trampoline, so the next AP can be started
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+// end of the double fault stack of the AP being started, see `gdt::init_ap`
+static AP_DOUBLE_FAULT_STACK: AtomicU64 = AtomicU64::new(0);
+// index the AP being started has to claim in `ap_entry`, `NO_CPU` once it did or the BSP gave up
+// on it
+static AP_EXPECTED_CPU: AtomicU64 = AtomicU64::new(NO_CPU);
+
+const NO_CPU: u64 = u64::MAX;
+
+#[derive(Debug)]
+pub enum SmpError {
+    // the APs are started through the local APIC
+    ApicDisabled,
+    // there is no usable page below 1MiB for the trampoline
+    NoLowMemory,
+    // the page table of the BSP is above 4GiB, the trampoline can only load a 32 bit CR3
+    PageTableTooHigh,
+    Map(MapToError<Size4KiB>),
+}
+
+// Starts all usable processors listed in the MADT and returns the number of CPUs running
+// (including the BSP). Must be called after `apic::init`.
+//
+// The stacks of the APs are mapped from new frames instead of being allocated on the (small)
+// heap. If the frames run out, the remaining APs are not started.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<usize, SmpError> {
+    if !apic::is_enabled() {
+        return Err(SmpError::ApicDisabled);
+    }
+    let madt = acpi::madt().ok_or(SmpError::ApicDisabled)?;
+    let (level_4_table, _) = Cr3::read();
+    if level_4_table.start_address().as_u64() > u32::MAX as u64 {
+        return Err(SmpError::PageTableTooHigh);
+    }
+
+    // the AP starts at `page * 4096` in real mode, so the page has to be below 1MiB. It is
+    // identity mapped, since the trampoline keeps running at that address once paging is enabled.
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(SmpError::NoLowMemory)?;
+    let phys = frame.start_address();
+    if phys.as_u64() >= 0x10_0000 {
+        return Err(SmpError::NoLowMemory);
+    }
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    match unsafe { mapper.identity_map(frame, flags, frame_allocator) } {
+        Ok(flush) => flush.flush(),
+        Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame => {}
+        Err(err) => return Err(SmpError::Map(err)),
+    }
+
+    let code = trampoline::code();
+    let base = memory::phys_to_virt(phys).as_mut_ptr::<u8>();
+    let patch_u32 = |offset: usize, value: u32| unsafe {
+        ptr::write_unaligned(base.add(offset) as *mut u32, value)
+    };
+    let patch_u64 = |offset: usize, value: u64| unsafe {
+        ptr::write_unaligned(base.add(offset) as *mut u64, value)
+    };
+    unsafe { ptr::copy_nonoverlapping(code.as_ptr(), base, code.len()) };
+    let phys = phys.as_u64() as u32;
+    // the 64 bit code follows the 4 byte offset and the 2 byte selector of the far jump
+    patch_u32(
+        trampoline::jump_target_offset(),
+        phys + trampoline::jump_target_offset() as u32 + 6,
+    );
+    // the GDT (two entries) lies right before its pointer
+    patch_u32(
+        trampoline::gdt_pointer_offset() + 2,
+        phys + trampoline::gdt_pointer_offset() as u32 - 16,
+    );
+    patch_u64(
+        trampoline::cr3_offset(),
+        level_4_table.start_address().as_u64(),
+    );
+    patch_u64(
+        trampoline::entry_offset(),
+        ap_entry as extern "C" fn(u64) -> ! as usize as u64,
+    );
+
+    let bsp_apic_id = lapic::id();
+    let mut cpus = 1;
+    // an AP that did not start keeps its index, so the indices of the running CPUs can have gaps
+    let mut next_cpu = 1;
+    for processor in madt.processors.iter() {
+        if !processor.usable || processor.apic_id == bsp_apic_id {
+            continue;
+        }
+        // the AP never returns, so its stacks are never unmapped. Those of an AP that did not start
+        // are not reused either, it might have written to them before it was stopped.
+        let stacks = map_ap_stacks(mapper, frame_allocator);
+        let (stack_end, double_fault_stack_end) = match stacks {
+            Ok(stacks) => stacks,
+            Err(err) if cpus == 1 => return Err(SmpError::Map(err)),
+            Err(err) => {
+                println!(
+                    "WARNING: no memory left for the stacks of the other CPUs ({:?})",
+                    err
+                );
+                break;
+            }
+        };
+        patch_u64(trampoline::stack_offset(), stack_end.as_u64());
+        patch_u64(trampoline::cpu_offset(), next_cpu);
+        AP_DOUBLE_FAULT_STACK.store(double_fault_stack_end.as_u64(), Ordering::Release);
+
+        let started = start_ap(processor.apic_id, (phys >> 12) as u8, next_cpu);
+        next_cpu += 1;
+        if started {
+            cpus += 1;
+        } else {
+            println!(
+                "WARNING: CPU with APIC id {} did not start",
+                processor.apic_id
+            );
+        }
+    }
+    Ok(cpus)
+}
+
+// Maps the stack and the double fault stack of an AP and returns their ends.
+fn map_ap_stacks(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(VirtAddr, VirtAddr), MapToError<Size4KiB>> {
+    let stack_end = memory::map_stack(AP_STACK_PAGES, mapper, frame_allocator)?;
+    let double_fault_stack_pages = (gdt::DOUBLE_FAULT_STACK_SIZE / 4096) as u64;
+    let double_fault_stack_end =
+        memory::map_stack(double_fault_stack_pages, mapper, frame_allocator)?;
+    Ok((stack_end, double_fault_stack_end))
+}
+
+// Sends the INIT-SIPI-SIPI sequence to the AP with the given local APIC id and waits for it to
+// reach `ap_entry` and claim the index `cpu`.
+fn start_ap(apic_id: u8, page: u8, cpu: u64) -> bool {
+    AP_STARTED.store(false, Ordering::Release);
+    AP_EXPECTED_CPU.store(cpu, Ordering::Release);
+    lapic::send_init_ipi(apic_id);
+    pit::busy_wait_us(10_000);
+    // the second SIPI is only needed if the first one got lost
+    for _ in 0..2 {
+        lapic::send_startup_ipi(apic_id, page);
+        pit::busy_wait_us(200);
+        if AP_STARTED.load(Ordering::Acquire) {
+            return true;
+        }
+    }
+    // give it 100ms to get through the trampoline
+    for _ in 0..100 {
+        if AP_STARTED.load(Ordering::Acquire) {
+            return true;
+        }
+        pit::busy_wait_us(1000);
+    }
+    // Taking the index back keeps an AP that is just slow from running `ap_entry` later. If it
+    // already claimed the index, it is past the trampoline and only has to finish.
+    if AP_EXPECTED_CPU
+        .compare_exchange(cpu, NO_CPU, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok()
+    {
+        // puts the AP back into wait-for-SIPI, so it does not run the trampoline once it is
+        // patched for the next AP
+        lapic::send_init_ipi(apic_id);
+        return false;
+    }
+    while !AP_STARTED.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+    true
+}
+
+// Entry point of an AP, called by the trampoline in long mode with interrupts disabled.
+extern "C" fn ap_entry(cpu: u64) -> ! {
+    let claimed = AP_EXPECTED_CPU
+        .compare_exchange(cpu, NO_CPU, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok();
+    assert!(claimed, "CPU {} started after the BSP gave up on it", cpu);
+    gdt::init_ap(VirtAddr::new(AP_DOUBLE_FAULT_STACK.load(Ordering::Acquire)));
+    interrupts::init_idt();
+    percpu::init(cpu as usize);
+    apic::init_ap();
+    AP_STARTED.store(true, Ordering::Release);
+
+    x86_64::instructions::interrupts::enable();
+    Executor::new().run();
+}
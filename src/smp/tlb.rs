@@ -0,0 +1,84 @@
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::{interrupts, tlb};
+use x86_64::structures::paging::{page::PageRange, Page, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::apic::{self, lapic};
+use crate::interrupts::InterruptsIndex;
+use crate::percpu;
+
+// Every CPU caches page table entries in its own TLB, and `MapperFlush::flush` only invalidates the
+// current CPU's entries. After a mapping was removed or restricted, the other CPUs might keep using
+// the stale entries, so the frame must not be reused before all of them flushed them too. This is
+// a TLB shootdown: the initiator sends an IPI to every other CPU and waits until all of them
+// acknowledged it.
+
+// above this many pages, flushing the whole TLB is cheaper than invalidating the pages one by one
+const MAX_SINGLE_PAGE_FLUSHES: u64 = 32;
+
+// only one shootdown can be in flight, its request is described by the atomics below
+static SHOOTDOWN: Mutex<()> = Mutex::new(());
+static START: AtomicU64 = AtomicU64::new(0);
+static PAGE_COUNT: AtomicU64 = AtomicU64::new(0);
+// number of CPUs that did not flush yet
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+// Invalidates the TLB entries of `page` on all CPUs.
+pub fn shootdown_page(page: Page<Size4KiB>) {
+    shootdown(Page::range(page, page + 1));
+}
+
+// Invalidates the TLB entries of `pages` on all CPUs and returns once every CPU flushed them.
+//
+// Must be called with interrupts enabled: two CPUs starting a shootdown at the same time would
+// otherwise wait for each other forever, since the one waiting for the lock could not answer the
+// IPI of the other.
+pub fn shootdown(pages: PageRange<Size4KiB>) {
+    let start = pages.start.start_address().as_u64();
+    let count = pages.count() as u64;
+    let others = percpu::cpu_count() - 1;
+    if others == 0 || !apic::is_enabled() {
+        flush(start, count);
+        return;
+    }
+    assert!(
+        interrupts::are_enabled(),
+        "TLB shootdown with interrupts disabled"
+    );
+
+    let _shootdown = SHOOTDOWN.lock();
+    START.store(start, Ordering::Relaxed);
+    PAGE_COUNT.store(count, Ordering::Relaxed);
+    // publishes the request to the interrupt handlers of the other CPUs
+    PENDING.store(others, Ordering::Release);
+    lapic::broadcast_ipi(InterruptsIndex::TlbShootdown.as_u8());
+    flush(start, count);
+    while PENDING.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+// Called by the TLB shootdown interrupt handler
+//
+// Must not block or allocate
+pub(crate) fn handle_shootdown() {
+    if PENDING.load(Ordering::Acquire) == 0 {
+        return;
+    }
+    flush(
+        START.load(Ordering::Relaxed),
+        PAGE_COUNT.load(Ordering::Relaxed),
+    );
+    PENDING.fetch_sub(1, Ordering::Release);
+}
+
+fn flush(start: u64, count: u64) {
+    if count > MAX_SINGLE_PAGE_FLUSHES {
+        tlb::flush_all();
+        return;
+    }
+    for i in 0..count {
+        tlb::flush(VirtAddr::new(start + i * 4096));
+    }
+}
@@ -0,0 +1,129 @@
+use core::arch::global_asm;
+
+// Code an application processor (AP) starts executing after the startup IPI. It is copied to a
+// page below 1MiB, since the AP starts in 16 bit real mode with CS pointing at that page.
+//
+// It switches directly from real mode to long mode: it loads a temporary GDT with a 64 bit code
+// segment, enables PAE, loads the page table of the bootstrap processor (which identity maps the
+// trampoline page), sets EFER.LME and finally enables protection and paging at once. The fields
+// at the end are filled in by `smp::init` before the AP is started.
+global_asm!(
+    ".code16",
+    ".global ap_trampoline_start",
+    "ap_trampoline_start:",
+    "cli",
+    "cld",
+    // Intel syntax does not allow the difference of two labels as address, so they are named
+    ".set AP_GDT_POINTER_OFFSET, ap_trampoline_gdt_pointer - ap_trampoline_start",
+    ".set AP_CR3_OFFSET, ap_trampoline_cr3 - ap_trampoline_start",
+    // the data is addressed relative to the start of the trampoline
+    "mov ax, cs",
+    "mov ds, ax",
+    "lgdt [AP_GDT_POINTER_OFFSET]",
+    // enable PAE
+    "mov eax, cr4",
+    "or eax, 0x20",
+    "mov cr4, eax",
+    "mov eax, dword ptr [AP_CR3_OFFSET]",
+    "mov cr3, eax",
+    // set the long mode enable bit of the EFER MSR
+    "mov ecx, 0xc0000080",
+    "rdmsr",
+    "or eax, 0x100",
+    "wrmsr",
+    // enable paging and protection
+    "mov eax, cr0",
+    "or eax, 0x80000001",
+    "mov cr0, eax",
+    // far jump to the 64 bit code segment, the offset is patched to the physical address of
+    // `ap_trampoline_long_mode`
+    ".byte 0x66, 0xea",
+    ".global ap_trampoline_jump_target",
+    "ap_trampoline_jump_target:",
+    ".long 0",
+    ".word 0x08",
+    ".code64",
+    "ap_trampoline_long_mode:",
+    "xor eax, eax",
+    "mov ss, ax",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov rsp, qword ptr [rip + ap_trampoline_stack]",
+    "mov rdi, qword ptr [rip + ap_trampoline_cpu]",
+    "call qword ptr [rip + ap_trampoline_entry]",
+    "ud2",
+    ".align 8",
+    "ap_trampoline_gdt:",
+    ".quad 0",
+    // present, executable, 64 bit code segment
+    ".quad 0x00af9a000000ffff",
+    ".global ap_trampoline_gdt_pointer",
+    "ap_trampoline_gdt_pointer:",
+    ".word 15",
+    // patched to the physical address of `ap_trampoline_gdt`
+    ".long 0",
+    ".align 8",
+    ".global ap_trampoline_cr3",
+    "ap_trampoline_cr3:",
+    ".quad 0",
+    ".global ap_trampoline_stack",
+    "ap_trampoline_stack:",
+    ".quad 0",
+    ".global ap_trampoline_cpu",
+    "ap_trampoline_cpu:",
+    ".quad 0",
+    ".global ap_trampoline_entry",
+    "ap_trampoline_entry:",
+    ".quad 0",
+    ".global ap_trampoline_end",
+    "ap_trampoline_end:",
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_jump_target: u8;
+    static ap_trampoline_gdt_pointer: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_cpu: u8;
+    static ap_trampoline_entry: u8;
+}
+
+// Returns the code of the trampoline.
+pub(super) fn code() -> &'static [u8] {
+    unsafe {
+        let start = &ap_trampoline_start as *const u8;
+        let end = &ap_trampoline_end as *const u8;
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
+
+// Offsets of the fields `smp::init` patches, relative to the start of the trampoline.
+fn offset(field: &u8) -> usize {
+    field as *const u8 as usize - unsafe { &ap_trampoline_start } as *const u8 as usize
+}
+
+pub(super) fn jump_target_offset() -> usize {
+    offset(unsafe { &ap_trampoline_jump_target })
+}
+
+pub(super) fn gdt_pointer_offset() -> usize {
+    offset(unsafe { &ap_trampoline_gdt_pointer })
+}
+
+pub(super) fn cr3_offset() -> usize {
+    offset(unsafe { &ap_trampoline_cr3 })
+}
+
+pub(super) fn stack_offset() -> usize {
+    offset(unsafe { &ap_trampoline_stack })
+}
+
+pub(super) fn cpu_offset() -> usize {
+    offset(unsafe { &ap_trampoline_cpu })
+}
+
+pub(super) fn entry_offset() -> usize {
+    offset(unsafe { &ap_trampoline_entry })
+}
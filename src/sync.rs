@@ -0,0 +1,15 @@
+// Spinlocks for kernel data shared between CPUs and with interrupt handlers. For the locks tasks
+// use to wait for each other (without spinning), see `task::sync`.
+//
+// Building with the `lock-debug` feature turns lockups and recursive acquisitions of the
+// `TicketLock` and `RwSpinLock` into panics that name the call sites involved.
+
+mod irq_safe_mutex;
+mod owner;
+mod rw_spin_lock;
+mod ticket_lock;
+
+pub use self::irq_safe_mutex::{IrqSafeMutex, IrqSafeMutexGuard};
+pub use self::owner::{set_lockup_threshold, DEFAULT_LOCKUP_TICKS};
+pub use self::rw_spin_lock::{RwSpinLock, RwSpinLockReadGuard, RwSpinLockWriteGuard};
+pub use self::ticket_lock::{TicketLock, TicketLockGuard};
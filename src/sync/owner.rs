@@ -0,0 +1,175 @@
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+use crate::task::executor;
+use crate::{percpu, thread};
+
+// The ids below are stored as `u64::MAX`/`usize::MAX` while they are not set.
+const NONE: u64 = u64::MAX;
+
+// Default for `set_lockup_threshold`, 5 seconds at the default timer frequency.
+pub const DEFAULT_LOCKUP_TICKS: u64 = 500;
+
+static LOCKUP_TICKS: AtomicU64 = AtomicU64::new(DEFAULT_LOCKUP_TICKS);
+
+// Sets after how many timer ticks of spinning on a lock a `lock-debug` build panics.
+pub fn set_lockup_threshold(ticks: u64) {
+    LOCKUP_TICKS.store(ticks, Ordering::Relaxed);
+}
+
+// Who holds a lock and where it was acquired. Only atomics, since the lock might be taken in an
+// interrupt handler; the fields are updated one by one, so a snapshot taken while the lock changes
+// hands can mix two owners. It is only used for diagnostics.
+pub(super) struct Owner {
+    cpu: AtomicUsize,
+    thread: AtomicU64,
+    task: AtomicU64,
+    location: AtomicPtr<Location<'static>>,
+}
+
+impl Owner {
+    pub(super) const fn new() -> Self {
+        Owner {
+            cpu: AtomicUsize::new(usize::MAX),
+            thread: AtomicU64::new(NONE),
+            task: AtomicU64::new(NONE),
+            location: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    // Records the current context as the owner, must be called right after acquiring the lock.
+    pub(super) fn set(&self, location: &'static Location<'static>) {
+        let (cpu, thread) = context();
+        self.cpu.store(cpu, Ordering::Relaxed);
+        self.thread.store(thread, Ordering::Relaxed);
+        let task = executor::current_task().map_or(NONE, |id| id.as_u64());
+        self.task.store(task, Ordering::Relaxed);
+        self.location
+            .store(location as *const _ as *mut _, Ordering::Relaxed);
+    }
+
+    // Must be called right before releasing the lock.
+    pub(super) fn clear(&self) {
+        self.location.store(ptr::null_mut(), Ordering::Relaxed);
+        self.cpu.store(usize::MAX, Ordering::Relaxed);
+        self.thread.store(NONE, Ordering::Relaxed);
+        self.task.store(NONE, Ordering::Relaxed);
+    }
+
+    fn location(&self) -> Option<&'static Location<'static>> {
+        unsafe { self.location.load(Ordering::Relaxed).as_ref() }
+    }
+
+    // Whether the lock is held by the code that is running right now. An interrupt handler counts
+    // as the code it interrupted, since that cannot release the lock until the handler returned.
+    #[cfg_attr(not(feature = "lock-debug"), allow(dead_code))]
+    fn is_current_context(&self) -> bool {
+        let (cpu, thread) = context();
+        self.location().is_some()
+            && self.cpu.load(Ordering::Relaxed) == cpu
+            && self.thread.load(Ordering::Relaxed) == thread
+    }
+}
+
+impl core::fmt::Display for Owner {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let location = match self.location() {
+            Some(location) => location,
+            None => return write!(f, "nobody"),
+        };
+        write!(f, "CPU {}", self.cpu.load(Ordering::Relaxed))?;
+        match self.thread.load(Ordering::Relaxed) {
+            NONE => {}
+            thread => write!(f, ", thread {}", thread)?,
+        }
+        match self.task.load(Ordering::Relaxed) {
+            NONE => {}
+            task => write!(f, ", task {}", task)?,
+        }
+        write!(f, " at {}", location)
+    }
+}
+
+// the CPU and the thread running right now
+fn context() -> (usize, u64) {
+    let thread = thread::current_unlocked().map_or(NONE, |id| id.as_u64());
+    (percpu::id(), thread)
+}
+
+// Busy waiting for a lock. In a `lock-debug` build it panics with both call sites when the lock is
+// held by the waiting context itself, or when it could not be acquired within the lockup
+// threshold.
+pub(super) struct Spin {
+    #[cfg(feature = "lock-debug")]
+    debug: SpinDebug,
+}
+
+#[cfg(feature = "lock-debug")]
+struct SpinDebug {
+    location: &'static Location<'static>,
+    spins: u64,
+    start: Option<crate::time::Instant>,
+}
+
+// the clock is only read every this many spins
+#[cfg(feature = "lock-debug")]
+const SPINS_PER_CHECK: u64 = 1024;
+
+impl Spin {
+    #[cfg_attr(not(feature = "lock-debug"), allow(unused_variables))]
+    pub(super) fn new(location: &'static Location<'static>) -> Self {
+        Spin {
+            #[cfg(feature = "lock-debug")]
+            debug: SpinDebug {
+                location,
+                spins: 0,
+                start: None,
+            },
+        }
+    }
+
+    // Called once per failed attempt to acquire a lock held by `owner`.
+    #[cfg_attr(not(feature = "lock-debug"), allow(unused_variables))]
+    pub(super) fn wait(&mut self, owner: &Owner) {
+        core::hint::spin_loop();
+        #[cfg(feature = "lock-debug")]
+        self.check(owner);
+    }
+
+    #[cfg(feature = "lock-debug")]
+    fn check(&mut self, owner: &Owner) {
+        use crate::time::{self, Duration, DEFAULT_FREQUENCY};
+
+        let debug = &mut self.debug;
+        debug.spins += 1;
+        if debug.spins == 1 && owner.is_current_context() {
+            panic!(
+                "lock acquired recursively at {}, it is held by {}",
+                debug.location, owner
+            );
+        }
+        if debug.spins % SPINS_PER_CHECK != 0 {
+            return;
+        }
+        // the tick counter does not advance while interrupts are disabled, so the elapsed time is
+        // measured with the (TSC based) precise clock and converted to ticks
+        let now = time::precise_now();
+        let start = *debug.start.get_or_insert(now);
+        let frequency = match time::frequency() {
+            0 => DEFAULT_FREQUENCY,
+            frequency => frequency,
+        };
+        let threshold = Duration::from_nanos(
+            LOCKUP_TICKS.load(Ordering::Relaxed) * 1_000_000_000 / u64::from(frequency),
+        );
+        if now.duration_since(start) > threshold {
+            panic!(
+                "lockup: spinning at {} for more than {} ticks, the lock is held by {}",
+                debug.location,
+                LOCKUP_TICKS.load(Ordering::Relaxed),
+                owner
+            );
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::owner::{Owner, Spin};
+
+// bits of `RwSpinLock::state`, the rest of it counts the readers
+const WRITER: usize = 1;
+const WRITER_WAITING: usize = 2;
+const READER: usize = 4;
+
+// A spinlock that lets any number of readers or a single writer in. A waiting writer keeps new
+// readers out, so writers cannot be starved by a steady stream of readers. This also means the
+// read lock is not reentrant: a second `read` deadlocks once a writer started waiting in between.
+//
+// The writer is recorded like the owner of a `TicketLock`, and a `lock-debug` build detects
+// lockups and recursive acquisitions of the write lock the same way. Readers are only counted.
+pub struct RwSpinLock<T: ?Sized> {
+    state: AtomicUsize,
+    writer: Owner,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwSpinLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwSpinLock<T> {}
+
+pub struct RwSpinLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwSpinLock<T>,
+}
+
+pub struct RwSpinLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwSpinLock<T>,
+}
+
+impl<T> RwSpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwSpinLock {
+            state: AtomicUsize::new(0),
+            writer: Owner::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwSpinLock<T> {
+    // Spins until neither a writer holds the lock nor one waits for it.
+    #[track_caller]
+    pub fn read(&self) -> RwSpinLockReadGuard<'_, T> {
+        let mut spin = Spin::new(Location::caller());
+        loop {
+            if let Some(guard) = self.try_read() {
+                return guard;
+            }
+            spin.wait(&self.writer);
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwSpinLockReadGuard<'_, T>> {
+        let state = self.state.load(Ordering::Relaxed);
+        if state & (WRITER | WRITER_WAITING) != 0 {
+            return None;
+        }
+        self.state
+            .compare_exchange_weak(state, state + READER, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        Some(RwSpinLockReadGuard { lock: self })
+    }
+
+    // Spins until all readers and the previous writer are gone.
+    #[track_caller]
+    pub fn write(&self) -> RwSpinLockWriteGuard<'_, T> {
+        let location = Location::caller();
+        let mut spin = Spin::new(location);
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            // only the waiting bit may be set, it is cleared by taking the lock; other waiting
+            // writers set it again on their next attempt
+            if state & !WRITER_WAITING == 0
+                && self
+                    .state
+                    .compare_exchange_weak(state, WRITER, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                self.writer.set(location);
+                return RwSpinLockWriteGuard { lock: self };
+            }
+            self.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+            spin.wait(&self.writer);
+        }
+    }
+
+    #[track_caller]
+    pub fn try_write(&self) -> Option<RwSpinLockWriteGuard<'_, T>> {
+        let state = self.state.load(Ordering::Relaxed);
+        if state & !WRITER_WAITING != 0 {
+            return None;
+        }
+        self.state
+            .compare_exchange(state, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        self.writer.set(Location::caller());
+        Some(RwSpinLockWriteGuard { lock: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T: ?Sized> Deref for RwSpinLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwSpinLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(READER, Ordering::Release);
+    }
+}
+
+impl<T: ?Sized> Deref for RwSpinLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwSpinLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwSpinLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.writer.clear();
+        // keeps the waiting bit of other writers
+        self.lock.state.fetch_and(!WRITER, Ordering::Release);
+    }
+}
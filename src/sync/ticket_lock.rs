@@ -0,0 +1,99 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::owner::{Owner, Spin};
+
+// A fair spinlock: every CPU draws a ticket and the lock is handed out in ticket order, so no CPU
+// can be starved by the others (which `spin::Mutex` allows).
+//
+// It records who holds it and where it was acquired. In a `lock-debug` build, spinning on it for
+// longer than the lockup threshold or acquiring it again while holding it panics with both call
+// sites, see `set_lockup_threshold`.
+//
+// Interrupts are left alone; a lock that is also taken by an interrupt handler has to be acquired
+// with interrupts disabled.
+pub struct TicketLock<T: ?Sized> {
+    next_ticket: AtomicUsize,
+    now_serving: AtomicUsize,
+    owner: Owner,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for TicketLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for TicketLock<T> {}
+
+pub struct TicketLockGuard<'a, T: ?Sized> {
+    lock: &'a TicketLock<T>,
+}
+
+impl<T> TicketLock<T> {
+    pub const fn new(value: T) -> Self {
+        TicketLock {
+            next_ticket: AtomicUsize::new(0),
+            now_serving: AtomicUsize::new(0),
+            owner: Owner::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> TicketLock<T> {
+    // Spins until it is this caller's turn.
+    #[track_caller]
+    pub fn lock(&self) -> TicketLockGuard<'_, T> {
+        let location = Location::caller();
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        let mut spin = Spin::new(location);
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            spin.wait(&self.owner);
+        }
+        self.owner.set(location);
+        TicketLockGuard { lock: self }
+    }
+
+    // Acquires the lock if nobody holds it or waits for it.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<TicketLockGuard<'_, T>> {
+        let ticket = self.now_serving.load(Ordering::Acquire);
+        self.next_ticket
+            .compare_exchange(ticket, ticket + 1, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        self.owner.set(Location::caller());
+        Some(TicketLockGuard { lock: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T: ?Sized> Deref for TicketLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for TicketLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for TicketLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.owner.clear();
+        self.lock.now_serving.fetch_add(1, Ordering::Release);
+    }
+}
@@ -0,0 +1,96 @@
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU32, Ordering},
+    task::{Context, Poll},
+};
+
+use futures_util::stream::Stream;
+
+use crate::percpu;
+
+// A stream that always has an item ready (e.g. a full queue) would let the task awaiting it run
+// forever without ever returning to the executor. To prevent this, every task gets a budget of
+// ready results each time it is polled. Once it is used up, budgeted streams return `Pending`
+// (after waking the task) so the task yields and the other tasks get their turn.
+
+// number of ready results a task can consume per poll
+pub const POLL_BUDGET: u32 = 128;
+
+// the budget before `percpu::init`, when only the BSP runs
+static BOOT_BUDGET: AtomicU32 = AtomicU32::new(POLL_BUDGET);
+
+// Every CPU polls its own tasks, so each one has its own budget.
+fn budget() -> &'static AtomicU32 {
+    match percpu::current() {
+        Some(per_cpu) => &per_cpu.budget,
+        None => &BOOT_BUDGET,
+    }
+}
+
+// Called by the executor before every poll of a task.
+pub(super) fn reset() {
+    budget().store(POLL_BUDGET, Ordering::Relaxed);
+}
+
+// Consumes one unit of the budget of the current task. Returns `Pending` if the budget is used
+// up, in that case the task is already woken again, so the caller can simply return `Pending`.
+pub fn poll_proceed(cx: &mut Context) -> Poll<()> {
+    let budget = budget();
+    let remaining = budget.load(Ordering::Relaxed);
+    if remaining == 0 {
+        cx.waker().wake_by_ref();
+        return Poll::Pending;
+    }
+    budget.store(remaining - 1, Ordering::Relaxed);
+    Poll::Ready(())
+}
+
+// Returns the remaining budget of the current task.
+pub fn remaining() -> u32 {
+    budget().load(Ordering::Relaxed)
+}
+
+// Makes `stream` respect the poll budget of the task polling it.
+pub fn budgeted<S: Stream>(stream: S) -> Budgeted<S> {
+    Budgeted { stream }
+}
+
+pub struct Budgeted<S> {
+    stream: S,
+}
+
+impl<S: Stream> Stream for Budgeted<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<S::Item>> {
+        if poll_proceed(cx).is_pending() {
+            return Poll::Pending;
+        }
+        // this is okay because `stream` is never moved out of `self`
+        let stream = unsafe { self.map_unchecked_mut(|s| &mut s.stream) };
+        stream.poll_next(cx)
+    }
+}
+
+// Yields to the executor once, so the other woken tasks can run.
+pub async fn yield_now() {
+    YieldNow { yielded: false }.await
+}
+
+struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
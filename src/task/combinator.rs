@@ -0,0 +1,155 @@
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use alloc::{boxed::Box, vec::Vec};
+use futures_util::future::{maybe_done, Either, MaybeDone};
+
+// Combinators to run several futures within one task. The futures are polled in place, so unlike
+// with `task::spawn` they can borrow from the caller and do not need to be `Send`.
+
+// Runs both futures to completion and returns both outputs.
+pub fn join<A: Future, B: Future>(a: A, b: B) -> Join<A, B> {
+    Join {
+        a: maybe_done(a),
+        b: maybe_done(b),
+    }
+}
+
+pub struct Join<A: Future, B: Future> {
+    a: MaybeDone<A>,
+    b: MaybeDone<B>,
+}
+
+impl<A: Future, B: Future> Future for Join<A, B> {
+    type Output = (A::Output, B::Output);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // this is okay because the fields are never moved out of `self`
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut a = unsafe { Pin::new_unchecked(&mut this.a) };
+        let mut b = unsafe { Pin::new_unchecked(&mut this.b) };
+        // both are polled every time, so each one registers its waker
+        let a_ready = a.as_mut().poll(cx).is_ready();
+        let b_ready = b.as_mut().poll(cx).is_ready();
+        if a_ready && b_ready {
+            Poll::Ready((a.take_output().unwrap(), b.take_output().unwrap()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+// Runs all futures to completion and returns their outputs in the same order.
+pub fn join_all<I>(futures: I) -> JoinAll<I::Item>
+where
+    I: IntoIterator,
+    I::Item: Future,
+{
+    let futures: Box<[_]> = futures.into_iter().map(maybe_done).collect();
+    JoinAll {
+        futures: Box::into_pin(futures),
+    }
+}
+
+pub struct JoinAll<F: Future> {
+    futures: Pin<Box<[MaybeDone<F>]>>,
+}
+
+impl<F: Future> Future for JoinAll<F> {
+    type Output = Vec<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Vec<F::Output>> {
+        let mut all_ready = true;
+        // this is okay because the boxed slice never moves its elements
+        let futures = unsafe { self.futures.as_mut().get_unchecked_mut() };
+        for future in futures.iter_mut() {
+            let future = unsafe { Pin::new_unchecked(future) };
+            all_ready &= future.poll(cx).is_ready();
+        }
+        if !all_ready {
+            return Poll::Pending;
+        }
+        let outputs = futures
+            .iter_mut()
+            .map(|future| unsafe { Pin::new_unchecked(future) }.take_output().unwrap())
+            .collect();
+        Poll::Ready(outputs)
+    }
+}
+
+// Waits for the first of the two futures to complete. The other one is dropped, i.e. cancelled.
+//
+// Note that dropping a `JoinHandle` detaches its task instead of cancelling it, use `abort` for
+// that.
+pub fn select<A: Future, B: Future>(a: A, b: B) -> Select<A, B> {
+    Select {
+        futures: Some((Box::pin(a), Box::pin(b))),
+    }
+}
+
+pub struct Select<A, B> {
+    futures: Option<(Pin<Box<A>>, Pin<Box<B>>)>,
+}
+
+impl<A: Future, B: Future> Future for Select<A, B> {
+    type Output = Either<A::Output, B::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let (a, b) = self
+            .futures
+            .as_mut()
+            .expect("Select polled after completion");
+        let output = if let Poll::Ready(output) = a.as_mut().poll(cx) {
+            Either::Left(output)
+        } else if let Poll::Ready(output) = b.as_mut().poll(cx) {
+            Either::Right(output)
+        } else {
+            return Poll::Pending;
+        };
+        // drop the loser
+        self.futures = None;
+        Poll::Ready(output)
+    }
+}
+
+// Waits for the first of the futures to complete and returns its output and index. The others
+// are dropped, i.e. cancelled.
+pub fn select_all<I>(futures: I) -> SelectAll<I::Item>
+where
+    I: IntoIterator,
+    I::Item: Future,
+{
+    let futures: Vec<_> = futures.into_iter().map(Box::pin).collect();
+    assert!(!futures.is_empty(), "select_all needs at least one future");
+    SelectAll { futures }
+}
+
+pub struct SelectAll<F> {
+    futures: Vec<Pin<Box<F>>>,
+}
+
+impl<F: Future> Future for SelectAll<F> {
+    type Output = (F::Output, usize);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let ready = self
+            .futures
+            .iter_mut()
+            .enumerate()
+            .find_map(|(index, future)| match future.as_mut().poll(cx) {
+                Poll::Ready(output) => Some((output, index)),
+                Poll::Pending => None,
+            });
+        match ready {
+            Some(ready) => {
+                // drop the losers
+                self.futures.clear();
+                Poll::Ready(ready)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
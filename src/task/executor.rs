@@ -0,0 +1,447 @@
+use core::{
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    format,
+    sync::Arc,
+    task::Wake,
+    vec::Vec,
+};
+use crossbeam_queue::ArrayQueue;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::{Priority, Task, TaskId};
+use crate::time::{self, tsc::CycleCounter, Duration, Instant};
+use crate::{percpu, println};
+
+// TSC cycles spent polling tasks, for benchmarking
+pub static POLL_CYCLES: CycleCounter = CycleCounter::new();
+
+// maximum number of woken tasks per priority class that can wait for their next poll
+const TASK_QUEUE_SIZE: usize = 100;
+
+// A single poll taking longer than this blocks every other task (and the keyboard echo), so it is
+// reported.
+pub const SLOW_POLL_THRESHOLD: Duration = Duration::from_millis(10);
+
+// Tasks created with `task::spawn`, they are moved into the executor before it polls the woken
+// tasks.
+static SPAWNED: Mutex<VecDeque<Task>> = Mutex::new(VecDeque::new());
+
+// Statistics of all tasks the executor currently owns, for `tasks`.
+static STATS: Mutex<BTreeMap<TaskId, Arc<TaskStats>>> = Mutex::new(BTreeMap::new());
+
+pub(super) fn push_spawned(task: Task) {
+    SPAWNED.lock().push_back(task);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    // woken and waiting in the queue of its priority class
+    Ready = 0,
+    // being polled right now
+    Running = 1,
+    // waiting for its waker to be invoked
+    Waiting = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WakeReason {
+    // the task was just spawned and not woken yet
+    Spawned = 0,
+    // woken by another task
+    Task = 1,
+    // woken by an interrupt handler
+    Interrupt = 2,
+    // woken by itself while being polled, e.g. through `budget::yield_now`
+    Yield = 3,
+}
+
+// Statistics of a task, shared with its waker. Only atomics, since the waker might run in an
+// interrupt handler.
+struct TaskStats {
+    id: TaskId,
+    name: &'static str,
+    priority: Priority,
+    spawned_at: Instant,
+    polls: AtomicU64,
+    poll_nanos: AtomicU64,
+    max_poll_nanos: AtomicU64,
+    state: AtomicU8,
+    last_wake: AtomicU8,
+}
+
+impl TaskStats {
+    fn record_poll(&self, elapsed: Duration) {
+        let nanos = elapsed.as_nanos() as u64;
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        self.poll_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.max_poll_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    fn info(&self) -> TaskInfo {
+        TaskInfo {
+            id: self.id,
+            name: self.name,
+            priority: self.priority,
+            spawned_at: self.spawned_at,
+            polls: self.polls.load(Ordering::Relaxed),
+            poll_time: Duration::from_nanos(self.poll_nanos.load(Ordering::Relaxed)),
+            max_poll_time: Duration::from_nanos(self.max_poll_nanos.load(Ordering::Relaxed)),
+            state: match self.state.load(Ordering::Relaxed) {
+                0 => TaskState::Ready,
+                1 => TaskState::Running,
+                _ => TaskState::Waiting,
+            },
+            last_wake: match self.last_wake.load(Ordering::Relaxed) {
+                0 => WakeReason::Spawned,
+                1 => WakeReason::Task,
+                2 => WakeReason::Interrupt,
+                _ => WakeReason::Yield,
+            },
+        }
+    }
+}
+
+// Snapshot of the statistics of a task, returned by `tasks`.
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: &'static str,
+    pub priority: Priority,
+    pub spawned_at: Instant,
+    // number of times the task was polled
+    pub polls: u64,
+    // time spent in all polls of the task, and in the longest one
+    pub poll_time: Duration,
+    pub max_poll_time: Duration,
+    pub state: TaskState,
+    pub last_wake: WakeReason,
+}
+
+// Returns the statistics of all tasks of the executor, ordered by their id (i.e. spawn order).
+pub fn tasks() -> Vec<TaskInfo> {
+    STATS.lock().values().map(|stats| stats.info()).collect()
+}
+
+// Returns the id of the task being polled on the current CPU, `None` outside of a poll.
+pub fn current_task() -> Option<TaskId> {
+    let per_cpu = percpu::current()?;
+    match per_cpu.current_task.load(Ordering::Relaxed) {
+        u64::MAX => None,
+        id => Some(TaskId(id)),
+    }
+}
+
+// Prints a `ps`-like listing of the tasks.
+pub fn print_tasks() {
+    // the derived `Debug` ignores the width, hence the `format!`s
+    println!(
+        "{:>4} {:<11} {:<7} {:>8} {:>12} {:<9} NAME",
+        "ID", "PRIORITY", "STATE", "POLLS", "POLL TIME", "WAKE"
+    );
+    for task in tasks() {
+        println!(
+            "{:>4} {:<11} {:<7} {:>8} {:>10}us {:<9} {}",
+            task.id,
+            format!("{:?}", task.priority),
+            format!("{:?}", task.state),
+            task.polls,
+            task.poll_time.as_micros(),
+            format!("{:?}", task.last_wake),
+            task.name
+        );
+    }
+}
+
+struct TaskEntry {
+    task: Task,
+    stats: Arc<TaskStats>,
+    // created on spawn, it queues the task on the executor that spawned it
+    waker: Waker,
+}
+
+// The tasks of all executors that are not being polled right now. A task is taken out while it is
+// polled, so every executor can run any task without holding the lock during the poll.
+static TASKS: Mutex<BTreeMap<TaskId, TaskEntry>> = Mutex::new(BTreeMap::new());
+
+// The task queues of all running executors (one per CPU), an idle executor steals woken tasks from
+// the others.
+static QUEUES: Mutex<Vec<TaskQueues>> = Mutex::new(Vec::new());
+
+type TaskQueues = [Arc<ArrayQueue<TaskId>>; 3];
+
+// Unlike the `SimpleExecutor`, this executor only polls a task after it was woken, and halts the
+// CPU while no task is ready. Every CPU runs its own executor; tasks are shared between them, so a
+// task might be polled on a different CPU every time.
+pub struct Executor {
+    // ids of the woken tasks, one queue per priority class. They are shared with the wakers, which
+    // might be invoked from interrupt handlers, so they are fixed size lock-free queues that never
+    // allocate on push.
+    task_queues: TaskQueues,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            task_queues: [
+                Arc::new(ArrayQueue::new(TASK_QUEUE_SIZE)),
+                Arc::new(ArrayQueue::new(TASK_QUEUE_SIZE)),
+                Arc::new(ArrayQueue::new(TASK_QUEUE_SIZE)),
+            ],
+        }
+    }
+
+    pub fn spawn(&mut self, task: Task) {
+        let task_id = task.id;
+        let queue = &self.task_queues[task.priority.as_usize()];
+        let stats = Arc::new(TaskStats {
+            id: task.id,
+            name: task.name,
+            priority: task.priority,
+            spawned_at: time::now(),
+            polls: AtomicU64::new(0),
+            poll_nanos: AtomicU64::new(0),
+            max_poll_nanos: AtomicU64::new(0),
+            state: AtomicU8::new(TaskState::Ready as u8),
+            last_wake: AtomicU8::new(WakeReason::Spawned as u8),
+        });
+        let waker = TaskWaker::new(task_id, queue.clone(), stats.clone());
+        STATS.lock().insert(task_id, stats.clone());
+        if TASKS
+            .lock()
+            .insert(task_id, TaskEntry { task, stats, waker })
+            .is_some()
+        {
+            panic!("task with same ID already in tasks");
+        }
+        queue.push(task_id).expect("task queue full");
+    }
+
+    pub fn run(&mut self) -> ! {
+        // registered here instead of in `new`, since an executor is never dropped once it runs
+        QUEUES.lock().push(self.task_queues.clone());
+        loop {
+            self.spawn_pending_tasks();
+            self.run_ready_tasks();
+            self.steal_task();
+            self.sleep_if_idle();
+        }
+    }
+
+    fn spawn_pending_tasks(&mut self) {
+        loop {
+            // the lock must not be held while spawning, `spawn` might be called again from within
+            let task = SPAWNED.lock().pop_front();
+            match task {
+                Some(task) => self.spawn(task),
+                None => break,
+            }
+        }
+    }
+
+    // Polls the tasks that were woken when it is called, in weighted rounds: every round takes up
+    // to `Priority::weight` tasks from each class, highest class first. Tasks woken in the meantime
+    // (including those that woke themselves) wait for the next call, so a task that keeps waking
+    // itself cannot starve the spawned tasks and the other classes.
+    fn run_ready_tasks(&mut self) {
+        let mut remaining = self.task_queues.each_ref().map(|queue| queue.len());
+        while remaining.iter().any(|&count| count > 0) {
+            for priority in Priority::ALL {
+                let queue = &self.task_queues[priority.as_usize()];
+                let remaining = &mut remaining[priority.as_usize()];
+                for _ in 0..priority.weight().min(*remaining) {
+                    *remaining -= 1;
+                    match queue.pop() {
+                        Ok(task_id) => run_task(task_id, queue),
+                        // stolen by another executor
+                        Err(_) => {
+                            *remaining = 0;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Runs one woken task of another executor, highest priority class first, if the own queues are
+    // empty.
+    fn steal_task(&mut self) {
+        if self.task_queues.iter().any(|queue| !queue.is_empty()) {
+            return;
+        }
+        for priority in Priority::ALL {
+            let stolen = {
+                let queues = QUEUES.lock();
+                queues
+                    .iter()
+                    .filter(|queues| !Arc::ptr_eq(&queues[0], &self.task_queues[0]))
+                    .find_map(|queues| {
+                        let queue = &queues[priority.as_usize()];
+                        queue.pop().ok().map(|task_id| (task_id, queue.clone()))
+                    })
+            };
+            if let Some((task_id, queue)) = stolen {
+                run_task(task_id, &queue);
+                return;
+            }
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        // an interrupt could wake a task between the check and the `hlt`, which would then only be
+        // run after the next interrupt. So the check is done with interrupts disabled, and
+        // `enable_and_hlt` re-enables them atomically with halting.
+        //
+        // Tasks woken on another CPU are only stolen after the next timer interrupt of this one.
+        interrupts::disable();
+        let others_idle = match QUEUES.try_lock() {
+            Some(queues) => queues
+                .iter()
+                .all(|queues| queues.iter().all(|queue| queue.is_empty())),
+            None => false,
+        };
+        if others_idle
+            && self.task_queues.iter().all(|queue| queue.is_empty())
+            // like `QUEUES`, a lock held by someone else (e.g. a preempted thread spawning a task)
+            // counts as not idle
+            && SPAWNED.try_lock().is_some_and(|spawned| spawned.is_empty())
+        {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+// Polls the task `task_id`, which was just popped from `queue`.
+fn run_task(task_id: TaskId, queue: &ArrayQueue<TaskId>) {
+    let entry = TASKS.lock().remove(&task_id);
+    let TaskEntry {
+        mut task,
+        stats,
+        waker,
+    } = match entry {
+        Some(entry) => entry,
+        None => {
+            // the task is being polled by another executor and woke up in the meantime, so it has
+            // to be polled again afterwards
+            if STATS.lock().contains_key(&task_id) {
+                queue.push(task_id).expect("task queue full");
+            }
+            // otherwise the task already finished, it was woken more than once
+            return;
+        }
+    };
+    let mut context = Context::from_waker(&waker);
+    let per_cpu = percpu::current();
+    if let Some(per_cpu) = per_cpu {
+        per_cpu.current_task.store(task_id.0, Ordering::Relaxed);
+    }
+
+    stats
+        .state
+        .store(TaskState::Running as u8, Ordering::Relaxed);
+    let start = time::precise_now();
+    let poll = {
+        let _measure = POLL_CYCLES.start();
+        task.poll(&mut context)
+    };
+    let elapsed = time::precise_now().duration_since(start);
+    if let Some(per_cpu) = per_cpu {
+        per_cpu.current_task.store(u64::MAX, Ordering::Relaxed);
+    }
+    stats.record_poll(elapsed);
+    if elapsed > SLOW_POLL_THRESHOLD {
+        println!(
+            "WARNING: task {} ({}) blocked the executor for {}ms in a single poll",
+            task_id,
+            stats.name,
+            elapsed.as_millis()
+        );
+    }
+
+    match poll {
+        Poll::Ready(()) => {
+            STATS.lock().remove(&task_id);
+        }
+        Poll::Pending => {
+            // unless the task woke itself during the poll
+            let _ = stats.state.compare_exchange(
+                TaskState::Running as u8,
+                TaskState::Waiting as u8,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+            TASKS
+                .lock()
+                .insert(task_id, TaskEntry { task, stats, waker });
+        }
+    }
+}
+
+struct TaskWaker {
+    task_id: TaskId,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    stats: Arc<TaskStats>,
+}
+
+impl TaskWaker {
+    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>, stats: Arc<TaskStats>) -> Waker {
+        Waker::from(Arc::new(TaskWaker {
+            task_id,
+            task_queue,
+            stats,
+        }))
+    }
+
+    // Called by interrupt handlers through `Waker::wake_by_ref`
+    //
+    // Must not block or allocate
+    fn wake_task(&self) {
+        // a task that is already in its queue is not pushed again, otherwise a task woken by every
+        // interrupt would fill up the queue before it gets polled
+        let previous =
+            match self
+                .stats
+                .state
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |state| {
+                    (state != TaskState::Ready as u8).then_some(TaskState::Ready as u8)
+                }) {
+                Ok(previous) => previous,
+                Err(_) => return,
+            };
+        // interrupt handlers run with interrupts disabled, tasks (almost always) with them enabled
+        let reason = if previous == TaskState::Running as u8 {
+            WakeReason::Yield
+        } else if !interrupts::are_enabled() {
+            WakeReason::Interrupt
+        } else {
+            WakeReason::Task
+        };
+        self.stats.last_wake.store(reason as u8, Ordering::Relaxed);
+        if self.task_queue.push(self.task_id).is_err() {
+            // the task keeps its previous state, so the next wake-up tries again
+            self.stats.state.store(previous, Ordering::Relaxed);
+            println!(
+                "WARNING: task queue full; task {} ({}) was not woken",
+                self.task_id, self.stats.name
+            );
+        }
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
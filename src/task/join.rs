@@ -0,0 +1,184 @@
+use core::{
+    future::Future,
+    mem,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use alloc::sync::Arc;
+use spin::Mutex;
+
+// Error returned by a `JoinHandle` whose task was aborted before it completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+// State shared between a spawned task and its `JoinHandle`.
+struct JoinState<T> {
+    output: Option<T>,
+    aborted: bool,
+    // the task completed, or noticed it was aborted and will be dropped by the executor
+    finished: bool,
+    // the task that awaits the `JoinHandle`
+    join_waker: Option<Waker>,
+    // the task waiting for this one to finish without taking its output (see `Watch`)
+    watch_waker: Option<Waker>,
+    // the spawned task itself, so `abort` can make the executor drop it right away
+    task_waker: Option<Waker>,
+}
+
+impl<T> JoinState<T> {
+    // Marks the task as finished, returning the wakers to wake.
+    fn finish(&mut self) -> [Option<Waker>; 2] {
+        self.finished = true;
+        self.task_waker = None;
+        [self.join_waker.take(), self.watch_waker.take()]
+    }
+}
+
+fn wake_all<const N: usize>(wakers: [Option<Waker>; N]) {
+    for waker in wakers.into_iter().flatten() {
+        waker.wake();
+    }
+}
+
+// Wraps the future of a spawned task, storing its output for the `JoinHandle`.
+pub(super) struct JoinTask<F: Future> {
+    future: F,
+    state: Arc<Mutex<JoinState<F::Output>>>,
+}
+
+// Creates the future the executor runs for a spawned task, and the handle to await its output.
+pub(super) fn new<F: Future>(future: F) -> (JoinTask<F>, JoinHandle<F::Output>) {
+    let state = Arc::new(Mutex::new(JoinState {
+        output: None,
+        aborted: false,
+        finished: false,
+        join_waker: None,
+        watch_waker: None,
+        task_waker: None,
+    }));
+    let handle = JoinHandle {
+        state: state.clone(),
+    };
+    (JoinTask { future, state }, handle)
+}
+
+impl<F: Future> Future for JoinTask<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        // this is okay because `future` is never moved out of `self`
+        let this = unsafe { self.get_unchecked_mut() };
+        {
+            let mut state = this.state.lock();
+            if state.aborted {
+                // the executor drops the task, and with it the future
+                let wakers = state.finish();
+                drop(state);
+                wake_all(wakers);
+                return Poll::Ready(());
+            }
+        }
+
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        match future.poll(cx) {
+            Poll::Ready(output) => {
+                let wakers = {
+                    let mut state = this.state.lock();
+                    state.output = Some(output);
+                    state.finish()
+                };
+                wake_all(wakers);
+                Poll::Ready(())
+            }
+            Poll::Pending => {
+                let mut state = this.state.lock();
+                match &state.task_waker {
+                    Some(waker) if waker.will_wake(cx.waker()) => {}
+                    _ => state.task_waker = Some(cx.waker().clone()),
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+// Type erased access to a spawned task, used by `Scope` to cancel its children and wait for them.
+pub(super) trait Watch: Send + Sync {
+    fn abort(&self);
+
+    // Returns `Ready` once the task completed or was dropped after being aborted.
+    fn poll_finished(&self, cx: &mut Context) -> Poll<()>;
+}
+
+impl<T: Send> Watch for Mutex<JoinState<T>> {
+    fn abort(&self) {
+        let wakers = {
+            let mut state = self.lock();
+            if state.finished || state.aborted {
+                return;
+            }
+            state.aborted = true;
+            // the watcher is only woken once the task was actually dropped
+            [state.task_waker.take(), state.join_waker.take()]
+        };
+        wake_all(wakers);
+    }
+
+    fn poll_finished(&self, cx: &mut Context) -> Poll<()> {
+        let mut state = self.lock();
+        if state.finished {
+            return Poll::Ready(());
+        }
+        state.watch_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+// Handle to a task created with `task::spawn`. Awaiting it yields the output of the task, or
+// `Cancelled` if the task was aborted. Dropping the handle detaches the task, it keeps running.
+pub struct JoinHandle<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+impl<T: Send + 'static> JoinHandle<T> {
+    // Cancels the task. Its future is dropped the next time the executor gets to it, without
+    // being polled again. Does nothing if the task already completed.
+    pub fn abort(&self) {
+        Watch::abort(&*self.state);
+    }
+
+    pub(super) fn watch(&self) -> Arc<dyn Watch> {
+        self.state.clone()
+    }
+}
+
+impl<T> JoinHandle<T> {
+    // Returns whether the task completed or was aborted.
+    pub fn is_finished(&self) -> bool {
+        let state = self.state.lock();
+        state.finished || state.aborted
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, Cancelled>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+        if let Some(output) = state.output.take() {
+            return Poll::Ready(Ok(output));
+        }
+        if state.aborted {
+            return Poll::Ready(Err(Cancelled));
+        }
+        if state.finished {
+            // the output was already taken, a `JoinHandle` must not be polled after completion
+            panic!("JoinHandle polled after completion");
+        }
+        let old_waker = mem::replace(&mut state.join_waker, Some(cx.waker().clone()));
+        drop(state);
+        drop(old_waker);
+        Poll::Pending
+    }
+}
@@ -1,13 +1,21 @@
+mod event;
+mod layout;
+mod scancode;
+
+pub use self::event::{KeyEvent, KeyEventStream, Modifiers};
+pub use self::layout::{layout, set_layout, Layout};
+pub use self::scancode::ScancodeSet;
+pub use pc_keyboard::{HandleControl, KeyCode, KeyState};
+
 use core::{
     pin::Pin,
     task::{Context, Poll},
 };
 
+use super::budget;
+use crate::{print, println};
 use conquer_once::spin::OnceCell;
 use crossbeam_queue::ArrayQueue;
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-
-use crate::{print, println};
 use futures_util::{stream::Stream, StreamExt};
 
 // since ArrayQueue::init() does heap allocation, we cannot initialize this as a static variable.
@@ -60,6 +68,11 @@ impl Stream for ScancodeStream {
             .try_get()
             .expect("scancode queue not initialized");
 
+        // a burst of input must not keep the task from yielding to the others
+        if budget::poll_proceed(cx).is_pending() {
+            return Poll::Pending;
+        }
+
         // fast path
         if let Ok(scancode) = queue.pop() {
             return Poll::Ready(Some(scancode));
@@ -77,17 +90,30 @@ impl Stream for ScancodeStream {
 }
 
 pub async fn print_keypresses() {
-    let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
+    let mut events = KeyEventStream::new();
 
-    while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => print!("{}", character),
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
-                }
+    while let Some(event) = events.next().await {
+        if !event.is_pressed() {
+            continue;
+        }
+        match event.character {
+            // control characters (Ctrl plus a letter) in caret notation, e.g. `^C`
+            Some(character) if (character as u32) < 0x20 && !"\n\t\x08".contains(character) => {
+                print!("^{}", (character as u8 + b'@') as char)
             }
+            Some(character) => print!("{}", character),
+            None => match event.code {
+                // modifier keys on their own print nothing
+                KeyCode::ShiftLeft
+                | KeyCode::ShiftRight
+                | KeyCode::ControlLeft
+                | KeyCode::ControlRight
+                | KeyCode::AltLeft
+                | KeyCode::AltRight
+                | KeyCode::CapsLock
+                | KeyCode::NumpadLock => {}
+                code => print!("{:?}", code),
+            },
         }
     }
 }
@@ -0,0 +1,182 @@
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_util::stream::Stream;
+use pc_keyboard::{DecodedKey, HandleControl, KeyCode, KeyState};
+
+use super::scancode::{Decoder, ScancodeSet};
+use super::ScancodeStream;
+use crate::ps2::{self, Leds};
+
+// State of the modifier keys at the time of a `KeyEvent`, including the event's own key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub alt_gr: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+// A key being pressed or released.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+    pub modifiers: Modifiers,
+    // the character the key produces with the current layout and modifiers, only set when a key
+    // is pressed. With `HandleControl::MapLettersToUnicode`, Ctrl plus a letter produces the
+    // matching control character (e.g. U+0003 for Ctrl+C).
+    pub character: Option<char>,
+}
+
+impl KeyEvent {
+    pub fn is_pressed(&self) -> bool {
+        self.state == KeyState::Down
+    }
+}
+
+// Tracks the left and right keys separately, so releasing one of them while the other one is
+// still held keeps the modifier active.
+#[derive(Debug, Clone, Copy)]
+struct ModifierKeys {
+    left_shift: bool,
+    right_shift: bool,
+    left_ctrl: bool,
+    right_ctrl: bool,
+    alt: bool,
+    alt_gr: bool,
+    caps_lock: bool,
+    num_lock: bool,
+    scroll_lock: bool,
+}
+
+impl ModifierKeys {
+    fn new() -> Self {
+        ModifierKeys {
+            left_shift: false,
+            right_shift: false,
+            left_ctrl: false,
+            right_ctrl: false,
+            alt: false,
+            alt_gr: false,
+            caps_lock: false,
+            // the keyboard starts with Num Lock on, like `pc_keyboard::Keyboard`
+            num_lock: true,
+            scroll_lock: false,
+        }
+    }
+
+    fn update(&mut self, code: KeyCode, state: KeyState) {
+        let down = state == KeyState::Down;
+        match code {
+            KeyCode::ShiftLeft => self.left_shift = down,
+            KeyCode::ShiftRight => self.right_shift = down,
+            KeyCode::ControlLeft => self.left_ctrl = down,
+            KeyCode::ControlRight => self.right_ctrl = down,
+            KeyCode::AltLeft => self.alt = down,
+            KeyCode::AltRight => self.alt_gr = down,
+            // the lock keys toggle when they are pressed
+            KeyCode::CapsLock if down => self.caps_lock = !self.caps_lock,
+            KeyCode::NumpadLock if down => self.num_lock = !self.num_lock,
+            KeyCode::ScrollLock if down => self.scroll_lock = !self.scroll_lock,
+            _ => {}
+        }
+    }
+
+    fn modifiers(&self) -> Modifiers {
+        Modifiers {
+            shift: self.left_shift || self.right_shift,
+            ctrl: self.left_ctrl || self.right_ctrl,
+            alt: self.alt,
+            alt_gr: self.alt_gr,
+            caps_lock: self.caps_lock,
+            num_lock: self.num_lock,
+            scroll_lock: self.scroll_lock,
+        }
+    }
+
+    fn leds(&self) -> Leds {
+        Leds {
+            scroll_lock: self.scroll_lock,
+            num_lock: self.num_lock,
+            caps_lock: self.caps_lock,
+        }
+    }
+}
+
+// Stream of the key presses and releases, decoded with the current keyboard layout. The scancode
+// set is chosen when the stream is created, after `ps2::init` found out whether the controller
+// translates.
+//
+// Like the `ScancodeStream` it reads from, there can only be one.
+pub struct KeyEventStream {
+    scancodes: ScancodeStream,
+    decoder: Decoder,
+    modifier_keys: ModifierKeys,
+}
+
+impl KeyEventStream {
+    pub fn new() -> Self {
+        KeyEventStream {
+            scancodes: ScancodeStream::new(),
+            decoder: Decoder::new(ScancodeSet::current(), HandleControl::MapLettersToUnicode),
+            modifier_keys: ModifierKeys::new(),
+        }
+    }
+
+    // Chooses whether Ctrl plus a letter produces a control character (the default) or the plain
+    // letter, see `KeyEvent::character`.
+    pub fn set_ctrl_handling(&mut self, handle_ctrl: HandleControl) {
+        self.decoder.set_ctrl_handling(handle_ctrl);
+    }
+
+    fn decode(&mut self, scancode: u8) -> Option<KeyEvent> {
+        let event = match self.decoder.add_byte(scancode) {
+            Ok(Some(event)) => event,
+            // the first byte of a multi-byte scancode, or garbage
+            Ok(None) | Err(_) => return None,
+        };
+        let (code, state) = (event.code, event.state);
+        let leds = self.modifier_keys.leds();
+        self.modifier_keys.update(code, state);
+        if self.modifier_keys.leds() != leds {
+            // sent in the background, waiting for the keyboard here would stall the executor
+            ps2::update_leds(self.modifier_keys.leds());
+        }
+        let character = match self.decoder.process_keyevent(event) {
+            Some(DecodedKey::Unicode(character)) if state == KeyState::Down => Some(character),
+            _ => None,
+        };
+        Some(KeyEvent {
+            code,
+            state,
+            modifiers: self.modifier_keys.modifiers(),
+            character,
+        })
+    }
+}
+
+impl Stream for KeyEventStream {
+    type Item = KeyEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<KeyEvent>> {
+        let this = self.get_mut();
+        loop {
+            // the scancode stream takes care of the poll budget
+            match Pin::new(&mut this.scancodes).poll_next(cx) {
+                Poll::Ready(Some(scancode)) => {
+                    if let Some(event) = this.decode(scancode) {
+                        return Poll::Ready(Some(event));
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
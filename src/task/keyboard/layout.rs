@@ -0,0 +1,117 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, KeyboardLayout, Modifiers};
+
+// The keyboard layouts `pc-keyboard` provides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Layout {
+    Us104 = 0,
+    Uk105 = 1,
+    De105 = 2,
+    // French AZERTY
+    Azerty = 3,
+    Dvorak104 = 4,
+    Jis109 = 5,
+}
+
+// The bootloader does not pass a command line to the kernel, so the layout used from boot on is
+// chosen with the `KEYBOARD_LAYOUT` environment variable when building, e.g.
+// `KEYBOARD_LAYOUT=de cargo run`. An unknown name fails the build.
+const BOOT_LAYOUT: Layout = match option_env!("KEYBOARD_LAYOUT") {
+    Some(name) => match Layout::from_name(name) {
+        Some(layout) => layout,
+        None => panic!("unknown KEYBOARD_LAYOUT, expected one of us, uk, de, fr, dvorak, jis"),
+    },
+    None => Layout::Us104,
+};
+
+static LAYOUT: AtomicU8 = AtomicU8::new(BOOT_LAYOUT as u8);
+
+impl Layout {
+    pub const ALL: [Layout; 6] = [
+        Layout::Us104,
+        Layout::Uk105,
+        Layout::De105,
+        Layout::Azerty,
+        Layout::Dvorak104,
+        Layout::Jis109,
+    ];
+
+    // the short name used by `from_name` and the `KEYBOARD_LAYOUT` build option
+    pub const fn name(self) -> &'static str {
+        match self {
+            Layout::Us104 => "us",
+            Layout::Uk105 => "uk",
+            Layout::De105 => "de",
+            Layout::Azerty => "fr",
+            Layout::Dvorak104 => "dvorak",
+            Layout::Jis109 => "jis",
+        }
+    }
+
+    // Looks up a layout by its short name. This is a `const fn` so the build option can be checked
+    // at compile time, hence the hand-written comparison.
+    pub const fn from_name(name: &str) -> Option<Layout> {
+        let mut i = 0;
+        while i < Layout::ALL.len() {
+            let layout = Layout::ALL[i];
+            let (a, b) = (layout.name().as_bytes(), name.as_bytes());
+            if a.len() == b.len() {
+                let mut j = 0;
+                while j < a.len() && a[j] == b[j] {
+                    j += 1;
+                }
+                if j == a.len() {
+                    return Some(layout);
+                }
+            }
+            i += 1;
+        }
+        None
+    }
+
+    fn from_u8(value: u8) -> Layout {
+        match value {
+            1 => Layout::Uk105,
+            2 => Layout::De105,
+            3 => Layout::Azerty,
+            4 => Layout::Dvorak104,
+            5 => Layout::Jis109,
+            _ => Layout::Us104,
+        }
+    }
+}
+
+// Returns the layout key presses are currently decoded with.
+pub fn layout() -> Layout {
+    Layout::from_u8(LAYOUT.load(Ordering::Relaxed))
+}
+
+// Switches the keyboard layout. It takes effect with the next key press; the modifier state (e.g.
+// a held Shift key) is kept.
+pub fn set_layout(layout: Layout) {
+    LAYOUT.store(layout as u8, Ordering::Relaxed);
+}
+
+// The layout type `pc_keyboard::Keyboard` is created with. `KeyboardLayout` has no `self`
+// parameter, so it cannot carry the selected layout; it is looked up on every key instead.
+pub(super) struct RuntimeLayout;
+
+impl KeyboardLayout for RuntimeLayout {
+    fn map_keycode(
+        keycode: KeyCode,
+        modifiers: &Modifiers,
+        handle_ctrl: HandleControl,
+    ) -> DecodedKey {
+        match layout() {
+            Layout::Us104 => layouts::Us104Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Uk105 => layouts::Uk105Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::De105 => layouts::De105Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Azerty => layouts::Azerty::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Dvorak104 => {
+                layouts::Dvorak104Key::map_keycode(keycode, modifiers, handle_ctrl)
+            }
+            Layout::Jis109 => layouts::Jis109Key::map_keycode(keycode, modifiers, handle_ctrl),
+        }
+    }
+}
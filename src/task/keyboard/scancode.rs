@@ -0,0 +1,167 @@
+use pc_keyboard::{
+    Error, HandleControl, KeyCode, KeyEvent, KeyState, Keyboard, ScancodeSet1, ScancodeSet2,
+};
+
+use super::layout::RuntimeLayout;
+use crate::ps2;
+
+// prefix of the extended keys
+const EXTENDED: u8 = 0xe0;
+// prefix of Pause, the only key with a sequence of its own
+const EXTENDED_PAUSE: u8 = 0xe1;
+// set 2 sends this before the code of a released key, set 1 sets the highest bit of the code
+const SET2_RELEASE: u8 = 0xf0;
+const SET1_RELEASE_BIT: u8 = 0x80;
+
+// longest sequence that is buffered: the release of Pause in set 2 (E1 F0 14 F0 77)
+const MAX_SEQUENCE: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    One,
+    Two,
+}
+
+impl ScancodeSet {
+    // The set the keyboard task receives: the keyboard is switched to set 2 by `ps2::init`, but
+    // unless the controller cannot translate, it turns that into set 1.
+    pub fn current() -> Self {
+        if ps2::translation_enabled() {
+            ScancodeSet::One
+        } else {
+            ScancodeSet::Two
+        }
+    }
+}
+
+enum AnyKeyboard {
+    Set1(Keyboard<RuntimeLayout, ScancodeSet1>),
+    Set2(Keyboard<RuntimeLayout, ScancodeSet2>),
+}
+
+// What a (partial) extended sequence turned out to be.
+enum Sequence {
+    Incomplete,
+    // the fake Shift presses around Print Screen (and a few other extended keys), which make it
+    // look like Shift+PrtSc to old software
+    Ignored,
+    // Print Screen and Pause, which `pc_keyboard` does not decode
+    Key(KeyCode, KeyState),
+    // an ordinary extended key, decoded by `pc_keyboard`
+    Other,
+}
+
+// Turns scancodes of either set into key events. Most of the decoding is done by
+// `pc_keyboard::Keyboard`, but the multi-byte sequences of Print Screen and Pause are recognized
+// here first.
+pub(super) struct Decoder {
+    set: ScancodeSet,
+    keyboard: AnyKeyboard,
+    // the bytes of the extended sequence being received
+    sequence: [u8; MAX_SEQUENCE],
+    len: usize,
+}
+
+impl Decoder {
+    pub(super) fn new(set: ScancodeSet, handle_ctrl: HandleControl) -> Self {
+        let keyboard = match set {
+            ScancodeSet::One => {
+                AnyKeyboard::Set1(Keyboard::new(RuntimeLayout, ScancodeSet1, handle_ctrl))
+            }
+            ScancodeSet::Two => {
+                AnyKeyboard::Set2(Keyboard::new(RuntimeLayout, ScancodeSet2, handle_ctrl))
+            }
+        };
+        Decoder {
+            set,
+            keyboard,
+            sequence: [0; MAX_SEQUENCE],
+            len: 0,
+        }
+    }
+
+    pub(super) fn set_ctrl_handling(&mut self, handle_ctrl: HandleControl) {
+        match &mut self.keyboard {
+            AnyKeyboard::Set1(keyboard) => keyboard.set_ctrl_handling(handle_ctrl),
+            AnyKeyboard::Set2(keyboard) => keyboard.set_ctrl_handling(handle_ctrl),
+        }
+    }
+
+    // Must see every key event (releases too), the `pc_keyboard::Keyboard` keeps its own modifier
+    // state.
+    pub(super) fn process_keyevent(&mut self, event: KeyEvent) -> Option<pc_keyboard::DecodedKey> {
+        match &mut self.keyboard {
+            AnyKeyboard::Set1(keyboard) => keyboard.process_keyevent(event),
+            AnyKeyboard::Set2(keyboard) => keyboard.process_keyevent(event),
+        }
+    }
+
+    pub(super) fn add_byte(&mut self, byte: u8) -> Result<Option<KeyEvent>, Error> {
+        if self.len == 0 && byte != EXTENDED && byte != EXTENDED_PAUSE {
+            return self.decode(byte);
+        }
+        self.sequence[self.len] = byte;
+        self.len += 1;
+        let sequence = self.classify();
+        if matches!(sequence, Sequence::Incomplete) {
+            return Ok(None);
+        }
+        let len = core::mem::replace(&mut self.len, 0);
+        match sequence {
+            Sequence::Incomplete | Sequence::Ignored => Ok(None),
+            Sequence::Key(code, state) => Ok(Some(KeyEvent { code, state })),
+            Sequence::Other => {
+                // the prefixes only change the decode state of `pc_keyboard`, the last byte
+                // completes the event
+                let sequence = self.sequence;
+                let mut result = Ok(None);
+                for &byte in &sequence[..len] {
+                    result = self.decode(byte);
+                }
+                result
+            }
+        }
+    }
+
+    fn decode(&mut self, byte: u8) -> Result<Option<KeyEvent>, Error> {
+        match &mut self.keyboard {
+            AnyKeyboard::Set1(keyboard) => keyboard.add_byte(byte),
+            AnyKeyboard::Set2(keyboard) => keyboard.add_byte(byte),
+        }
+    }
+
+    fn classify(&self) -> Sequence {
+        let sequence = &self.sequence[..self.len];
+        match self.set {
+            ScancodeSet::One => match sequence {
+                [EXTENDED] | [EXTENDED_PAUSE] | [EXTENDED_PAUSE, _] => Sequence::Incomplete,
+                [EXTENDED, 0x2a | 0xaa | 0x36 | 0xb6] => Sequence::Ignored,
+                [EXTENDED, 0x37] => Sequence::Key(KeyCode::PrintScreen, KeyState::Down),
+                [EXTENDED, 0xb7] => Sequence::Key(KeyCode::PrintScreen, KeyState::Up),
+                // Pause is E1 1D 45 when pressed and E1 9D C5 when released, both sent at once
+                [EXTENDED_PAUSE, code, _] if code & SET1_RELEASE_BIT != 0 => {
+                    Sequence::Key(KeyCode::PauseBreak, KeyState::Up)
+                }
+                [EXTENDED_PAUSE, _, _] => Sequence::Key(KeyCode::PauseBreak, KeyState::Down),
+                _ => Sequence::Other,
+            },
+            ScancodeSet::Two => match sequence {
+                [EXTENDED] | [EXTENDED, SET2_RELEASE] => Sequence::Incomplete,
+                [EXTENDED, 0x12 | 0x59] | [EXTENDED, SET2_RELEASE, 0x12 | 0x59] => {
+                    Sequence::Ignored
+                }
+                [EXTENDED, 0x7c] => Sequence::Key(KeyCode::PrintScreen, KeyState::Down),
+                [EXTENDED, SET2_RELEASE, 0x7c] => Sequence::Key(KeyCode::PrintScreen, KeyState::Up),
+                // Pause is E1 14 77 when pressed and E1 F0 14 F0 77 when released
+                [EXTENDED_PAUSE, SET2_RELEASE, _, _, _] => {
+                    Sequence::Key(KeyCode::PauseBreak, KeyState::Up)
+                }
+                [EXTENDED_PAUSE, code, _] if *code != SET2_RELEASE => {
+                    Sequence::Key(KeyCode::PauseBreak, KeyState::Down)
+                }
+                [EXTENDED_PAUSE, ..] => Sequence::Incomplete,
+                _ => Sequence::Other,
+            },
+        }
+    }
+}
@@ -1,34 +1,196 @@
 use core::{
+    any, fmt,
     future::Future,
     pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
     task::{Context, Poll},
 };
 
 use alloc::boxed::Box;
 
+pub use self::combinator::{join, join_all, select, select_all};
+pub use self::join::{Cancelled, JoinHandle};
+pub use self::scope::{scope, Scope};
+
+pub mod budget;
+pub mod combinator;
+pub mod executor;
+mod join;
 pub mod keyboard;
+pub mod mouse;
+mod scope;
 pub mod simple_executor;
+pub mod sync;
+pub mod timer;
+
+// Spawns a task on the executor and returns a handle to await its output. Can be called from any
+// task (or before the executor runs), but not from an interrupt handler since it allocates.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    Builder::new().spawn(future)
+}
+
+// Like `spawn`, but the task is scheduled in the given priority class.
+pub fn spawn_with_priority<F>(future: F, priority: Priority) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    Builder::new().priority(priority).spawn(future)
+}
+
+// Configures a task before spawning it.
+pub struct Builder {
+    name: Option<&'static str>,
+    priority: Priority,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Builder {
+            name: None,
+            priority: Priority::Interactive,
+        }
+    }
+
+    // Sets the name shown by `executor::tasks`. Defaults to the type name of the future.
+    pub fn name(mut self, name: &'static str) -> Self {
+        self.name = Some(name);
+        self
+    }
+
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn spawn<F>(self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let name = self.name.unwrap_or_else(any::type_name::<F>);
+        let (task, handle) = join::new(future);
+        executor::push_spawned(Task::with_priority(task, self.priority).named(name));
+        handle
+    }
+}
+
+// Scheduling class of a task. The executor polls the woken tasks of every class in turn, taking
+// up to `weight` tasks from each, so higher classes get more CPU time but a busy class can never
+// starve a lower one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    // work deferred from interrupt handlers, e.g. decoding device input
+    Interrupt = 0,
+    // tasks the user is waiting for, e.g. echoing keypresses
+    Interactive = 1,
+    // long running work that should not disturb the other classes
+    Background = 2,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 3] = [
+        Priority::Interrupt,
+        Priority::Interactive,
+        Priority::Background,
+    ];
+
+    // Returns how many tasks of this class are polled per scheduling round.
+    pub fn weight(self) -> usize {
+        match self {
+            Priority::Interrupt => 8,
+            Priority::Interactive => 4,
+            Priority::Background => 1,
+        }
+    }
+
+    fn as_usize(self) -> usize {
+        self as usize
+    }
+}
 
 pub struct Task {
+    id: TaskId,
+    name: &'static str,
+    priority: Priority,
     // dyn keyword allows us to set the type parameter of the Box as anything that implements the
     // Future trais (trait objects). Rust will use dynamic dispatch (where the methods to be called
     // are calculated at runtime) for calling the methods of the trait object
     //
     // we use Pin because the futures created by async/await might be self-referential
-    future: Pin<Box<dyn Future<Output = ()>>>,
+    //
+    // tasks have to be `Send` since `spawn` hands them to the executor through a global queue
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
 }
 
 impl Task {
     // by using 'static here, I am affirming that the `future` will be valid for the whole lifetime
     // of the program
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Self {
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Self {
+        Task::with_priority(future, Priority::Interactive)
+    }
+
+    pub fn with_priority<F>(future: F, priority: Priority) -> Self
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
         Task {
+            id: TaskId::new(),
+            name: any::type_name::<F>(),
+            priority,
             future: Box::pin(future),
         }
     }
+
+    // Sets the name shown by `executor::tasks`. Defaults to the type name of the future.
+    pub fn named(mut self, name: &'static str) -> Self {
+        self.name = name;
+        self
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        budget::reset();
         // we use .as_mut because the `poll` method of the future requires to be called on
         // Pin<&mutT>
         self.future.as_mut().poll(context)
     }
 }
+
+// Unique identifier of a task, used by the executor to find the task a waker belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> Self {
+        // the counter only has to hand out unique values, so no ordering with other memory
+        // operations is needed
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
@@ -0,0 +1,187 @@
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use alloc::collections::VecDeque;
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use futures_util::{stream::Stream, task::AtomicWaker};
+
+use super::budget;
+use crate::time::{self, Duration, Instant};
+use crate::{println, ps2};
+
+// the bytes received from the mouse and when they arrived, like `keyboard::SCANCODE_QUEUE`
+static MOUSE_QUEUE: OnceCell<ArrayQueue<(u8, Instant)>> = OnceCell::uninit();
+
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+// bits of the first byte of a packet
+const LEFT_BUTTON: u8 = 1 << 0;
+const RIGHT_BUTTON: u8 = 1 << 1;
+const MIDDLE_BUTTON: u8 = 1 << 2;
+// always set, used to find the start of a packet again after bytes were lost
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+// The bytes of a packet arrive about a millisecond apart, the packets themselves at most 100 times
+// per second. A longer pause in the middle of a packet means its start was lost, so the bytes
+// received so far are dropped.
+const MAX_BYTE_GAP: Duration = Duration::from_millis(5);
+
+// Called by the mouse interrupt handler
+//
+// Must not block or allocate
+pub(crate) fn add_mouse_byte(byte: u8) {
+    if let Ok(queue) = MOUSE_QUEUE.try_get() {
+        if queue.push((byte, time::precise_now())).is_err() {
+            println!("WARNING: mouse queue full; dropping mouse input");
+        } else {
+            WAKER.wake();
+        }
+    }
+    // without a `MouseStream` nobody is interested in the mouse, so the bytes are dropped silently
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseEvent {
+    // relative motion in mouse units (counts); `dy` grows downwards, like the rows on the screen
+    Moved { dx: i16, dy: i16 },
+    // the scroll wheel turned by this many notches, positive values scroll down
+    Scrolled(i8),
+    ButtonPressed(MouseButton),
+    ButtonReleased(MouseButton),
+}
+
+// Stream of the mouse events, decoded from the packets of the PS/2 mouse. `ps2::init_mouse` must
+// have succeeded, otherwise the stream never yields anything.
+//
+// Like the `ScancodeStream`, there can only be one.
+pub struct MouseStream {
+    packet: [u8; 4],
+    received: usize,
+    // when the last byte arrived
+    last_byte: Instant,
+    buttons: u8,
+    // a packet can produce several events
+    pending: VecDeque<MouseEvent>,
+}
+
+impl MouseStream {
+    pub fn new() -> Self {
+        MOUSE_QUEUE
+            .try_init_once(|| ArrayQueue::new(100))
+            .expect("MouseStream::new should only be called once");
+        MouseStream {
+            packet: [0; 4],
+            received: 0,
+            last_byte: time::now(),
+            buttons: 0,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn add_byte(&mut self, byte: u8, arrived: Instant) {
+        if self.received > 0 && arrived.duration_since(self.last_byte) > MAX_BYTE_GAP {
+            self.received = 0;
+        }
+        self.last_byte = arrived;
+        // the first byte of a packet always has bit 3 set, anything else is the rest of a packet
+        // whose start was lost. A movement byte can have bit 3 set too, but hardly ever looks like
+        // a packet with both overflow bits set, which would be dropped anyway.
+        if self.received == 0
+            && (byte & ALWAYS_ONE == 0
+                || byte & (X_OVERFLOW | Y_OVERFLOW) == X_OVERFLOW | Y_OVERFLOW)
+        {
+            return;
+        }
+        self.packet[self.received] = byte;
+        self.received += 1;
+        let packet_size = if ps2::mouse_has_wheel() { 4 } else { 3 };
+        if self.received == packet_size {
+            self.received = 0;
+            self.decode_packet(packet_size == 4);
+        }
+    }
+
+    fn decode_packet(&mut self, has_wheel: bool) {
+        let [flags, x, y, z] = self.packet;
+
+        let buttons = flags & (LEFT_BUTTON | RIGHT_BUTTON | MIDDLE_BUTTON);
+        for (bit, button) in [
+            (LEFT_BUTTON, MouseButton::Left),
+            (RIGHT_BUTTON, MouseButton::Right),
+            (MIDDLE_BUTTON, MouseButton::Middle),
+        ] {
+            match (self.buttons & bit != 0, buttons & bit != 0) {
+                (false, true) => self.pending.push_back(MouseEvent::ButtonPressed(button)),
+                (true, false) => self.pending.push_back(MouseEvent::ButtonReleased(button)),
+                _ => {}
+            }
+        }
+        self.buttons = buttons;
+
+        // the movement is a 9 bit two's complement number, the sign bits are in the first byte. An
+        // overflowing movement is garbage and dropped.
+        if flags & (X_OVERFLOW | Y_OVERFLOW) == 0 {
+            let dx = i16::from(x) - if flags & X_SIGN != 0 { 256 } else { 0 };
+            let dy = i16::from(y) - if flags & Y_SIGN != 0 { 256 } else { 0 };
+            if dx != 0 || dy != 0 {
+                // the mouse counts upwards movement as positive
+                self.pending.push_back(MouseEvent::Moved { dx, dy: -dy });
+            }
+        }
+
+        if has_wheel {
+            // the movement of the wheel is a 4 bit two's complement number, the upper bits are
+            // used for extra buttons by some mice
+            let dz = ((z << 4) as i8) >> 4;
+            if dz != 0 {
+                self.pending.push_back(MouseEvent::Scrolled(dz));
+            }
+        }
+    }
+}
+
+impl Stream for MouseStream {
+    type Item = MouseEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<MouseEvent>> {
+        let this = self.get_mut();
+        let queue = MOUSE_QUEUE.try_get().expect("mouse queue not initialized");
+
+        loop {
+            if let Some(event) = this.pending.pop_front() {
+                return Poll::Ready(Some(event));
+            }
+            // a fast moving mouse must not keep the task from yielding to the others
+            if budget::poll_proceed(cx).is_pending() {
+                return Poll::Pending;
+            }
+            match queue.pop() {
+                Ok((byte, arrived)) => this.add_byte(byte, arrived),
+                Err(_) => {
+                    WAKER.register(cx.waker());
+                    match queue.pop() {
+                        Ok((byte, arrived)) => {
+                            WAKER.take();
+                            this.add_byte(byte, arrived);
+                        }
+                        Err(_) => return Poll::Pending,
+                    }
+                }
+            }
+        }
+    }
+}
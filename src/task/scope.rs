@@ -0,0 +1,76 @@
+use core::{future::Future, task::Poll};
+
+use alloc::{sync::Arc, vec::Vec};
+use futures_util::future::poll_fn;
+use spin::Mutex;
+
+use super::{join::Watch, JoinHandle};
+
+// Runs `f` with a `Scope` to spawn child tasks on, and only returns once all children finished.
+// If the returned future is dropped before that, the children are aborted, so no child outlives
+// its scope.
+//
+// The children are normal executor tasks, so they have to be `Send + 'static` like with
+// `task::spawn`.
+pub async fn scope<F, Fut>(f: F) -> Fut::Output
+where
+    F: FnOnce(Scope) -> Fut,
+    Fut: Future,
+{
+    let scope = Scope {
+        children: Arc::new(Mutex::new(Vec::new())),
+    };
+    // aborts the children if this future is dropped early, does nothing once they all finished
+    let _guard = CancelOnDrop(scope.clone());
+    let output = f(scope.clone()).await;
+    scope.wait().await;
+    output
+}
+
+// Handle to spawn children of a `scope`. It can be cloned and moved into the children, so they
+// can spawn further children of the same scope.
+#[derive(Clone)]
+pub struct Scope {
+    children: Arc<Mutex<Vec<Arc<dyn Watch>>>>,
+}
+
+impl Scope {
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let handle = super::spawn(future);
+        self.children.lock().push(handle.watch());
+        handle
+    }
+
+    // Aborts all children that are still running.
+    pub fn cancel(&self) {
+        for child in self.children.lock().iter() {
+            child.abort();
+        }
+    }
+
+    // Waits until every child finished, including children spawned while waiting.
+    async fn wait(&self) {
+        poll_fn(|cx| {
+            let mut children = self.children.lock();
+            children.retain(|child| child.poll_finished(cx).is_pending());
+            if children.is_empty() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+struct CancelOnDrop(Scope);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
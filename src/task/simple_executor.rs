@@ -1,6 +1,10 @@
 use alloc::collections::VecDeque;
 
 use super::Task;
+use crate::time::tsc::CycleCounter;
+
+// TSC cycles spent polling tasks, for benchmarking
+pub static POLL_CYCLES: CycleCounter = CycleCounter::new();
 
 pub struct SimpleExecutor {
     task_queue: VecDeque<Task>,
@@ -19,7 +23,11 @@ impl SimpleExecutor {
         while let Some(mut task) = self.task_queue.pop_front() {
             let dummy_waker = dummy_waker();
             let mut ctx = Context::from_waker(&dummy_waker);
-            match task.poll(&mut ctx) {
+            let poll = {
+                let _measure = POLL_CYCLES.start();
+                task.poll(&mut ctx)
+            };
+            match poll {
                 Poll::Ready(()) => {}
                 Poll::Pending => self.task_queue.push_back(task),
             }
@@ -0,0 +1,16 @@
+// Primitives for tasks to communicate with each other (and with interrupt handlers). They are
+// built on wakers, so a task waiting for a message or a lock is suspended instead of spinning.
+// Unlike `spin::Mutex`, the locks in here can be held across an `.await`.
+
+pub mod broadcast;
+pub mod mpsc;
+mod mutex;
+mod notify;
+pub mod oneshot;
+mod rwlock;
+mod semaphore;
+
+pub use self::mutex::{Mutex, MutexGuard};
+pub use self::notify::{Notified, Notify};
+pub use self::rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use self::semaphore::{Acquire, Semaphore, SemaphorePermit};
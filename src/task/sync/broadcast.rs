@@ -0,0 +1,219 @@
+use core::{
+    future::Future,
+    mem,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// Multi-producer, multi-consumer channel where every receiver gets every value. The last
+// `capacity` values are kept; a receiver that falls further behind skips the oldest ones and is
+// told how many it missed.
+//
+// Sending takes a lock and wakes the receivers, so it must only be done by tasks.
+
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "broadcast channel capacity must be non-zero");
+    let shared = Arc::new(Mutex::new(Shared {
+        values: VecDeque::with_capacity(capacity),
+        capacity,
+        next_seq: 0,
+        senders: 1,
+        receivers: 1,
+        wakers: Vec::new(),
+    }));
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver {
+            shared,
+            next_seq: 0,
+        },
+    )
+}
+
+struct Shared<T> {
+    values: VecDeque<T>,
+    capacity: usize,
+    // sequence number of the next value sent; the oldest value in `values` has the number
+    // `next_seq - values.len()`
+    next_seq: u64,
+    senders: usize,
+    receivers: usize,
+    // receivers waiting for the next value
+    wakers: Vec<Waker>,
+}
+
+impl<T> Shared<T> {
+    fn oldest_seq(&self) -> u64 {
+        self.next_seq - self.values.len() as u64
+    }
+}
+
+// the lock is taken with interrupts disabled, so a waker invoked by an interrupt handler never
+// runs into it
+fn lock<T, R>(shared: &Mutex<Shared<T>>, f: impl FnOnce(&mut Shared<T>) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut shared.lock()))
+}
+
+// Error returned by `send` when there are no receivers. Contains the value that was not sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    // all senders are gone and every value was received
+    Closed,
+    // the receiver fell behind and the given number of values was dropped before it saw them
+    Lagged(u64),
+}
+
+pub struct Sender<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> Sender<T> {
+    // Sends `value` to all receivers, dropping the oldest value if the channel is full. Never
+    // waits. Returns the number of receivers.
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        let (receivers, wakers) = lock(&self.shared, |shared| {
+            if shared.receivers == 0 {
+                return Err(SendError(value));
+            }
+            if shared.values.len() == shared.capacity {
+                shared.values.pop_front();
+            }
+            shared.values.push_back(value);
+            shared.next_seq += 1;
+            Ok((shared.receivers, mem::take(&mut shared.wakers)))
+        })?;
+        for waker in wakers {
+            waker.wake();
+        }
+        Ok(receivers)
+    }
+
+    // Creates a receiver that gets all values sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let next_seq = lock(&self.shared, |shared| {
+            shared.receivers += 1;
+            shared.next_seq
+        });
+        Receiver {
+            shared: self.shared.clone(),
+            next_seq,
+        }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        lock(&self.shared, |shared| shared.receivers)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        lock(&self.shared, |shared| shared.senders += 1);
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let wakers = lock(&self.shared, |shared| {
+            shared.senders -= 1;
+            if shared.senders == 0 {
+                mem::take(&mut shared.wakers)
+            } else {
+                Vec::new()
+            }
+        });
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+    // sequence number of the next value this receiver gets
+    next_seq: u64,
+}
+
+impl<T: Clone> Receiver<T> {
+    // Receives the next value.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv { receiver: self }
+    }
+
+    // Receives the next value without waiting. Returns `None` if there is none yet.
+    pub fn try_recv(&mut self) -> Option<Result<T, RecvError>> {
+        let shared = self.shared.clone();
+        lock(&shared, |shared| self.next(shared))
+    }
+
+    fn next(&mut self, shared: &mut Shared<T>) -> Option<Result<T, RecvError>> {
+        let oldest = shared.oldest_seq();
+        if self.next_seq < oldest {
+            let missed = oldest - self.next_seq;
+            self.next_seq = oldest;
+            return Some(Err(RecvError::Lagged(missed)));
+        }
+        if self.next_seq < shared.next_seq {
+            let value = shared.values[(self.next_seq - oldest) as usize].clone();
+            self.next_seq += 1;
+            return Some(Ok(value));
+        }
+        if shared.senders == 0 {
+            return Some(Err(RecvError::Closed));
+        }
+        None
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+        let shared = self.shared.clone();
+        lock(&shared, |shared| match self.next(shared) {
+            Some(result) => Poll::Ready(result),
+            None => {
+                if !shared.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                    shared.wakers.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        lock(&self.shared, |shared| shared.receivers += 1);
+        Receiver {
+            shared: self.shared.clone(),
+            next_seq: self.next_seq,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        lock(&self.shared, |shared| shared.receivers -= 1);
+    }
+}
+
+// Future returned by `Receiver::recv`.
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T: Clone> Future for Recv<'_, T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.receiver.poll_recv(cx)
+    }
+}
@@ -0,0 +1,317 @@
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use alloc::{collections::VecDeque, sync::Arc};
+use crossbeam_queue::{ArrayQueue, SegQueue};
+use futures_util::{stream::Stream, task::AtomicWaker};
+use spin::Mutex;
+
+use crate::task::budget;
+
+// Multi-producer, single-consumer channel. The values are kept in a lock-free queue, so a bounded
+// channel can be fed from an interrupt handler with `try_send`.
+
+// Creates a channel that holds up to `capacity` values. `send` waits while it is full.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must be non-zero");
+    new(Queue::Bounded(ArrayQueue::new(capacity)))
+}
+
+// Creates a channel without a limit, `send` never waits. Pushing to it allocates, so it must not
+// be used from interrupt handlers.
+pub fn unbounded_channel<T>() -> (Sender<T>, Receiver<T>) {
+    new(Queue::Unbounded(SegQueue::new()))
+}
+
+fn new<T>(queue: Queue<T>) -> (Sender<T>, Receiver<T>) {
+    let chan = Arc::new(Chan {
+        queue,
+        receiver_waker: AtomicWaker::new(),
+        sender_waiters: Mutex::new(SenderWaiters {
+            waiters: VecDeque::new(),
+            next_id: 0,
+        }),
+        senders: AtomicUsize::new(1),
+        closed: AtomicBool::new(false),
+    });
+    (Sender { chan: chan.clone() }, Receiver { chan })
+}
+
+enum Queue<T> {
+    Bounded(ArrayQueue<T>),
+    Unbounded(SegQueue<T>),
+}
+
+struct Chan<T> {
+    queue: Queue<T>,
+    receiver_waker: AtomicWaker,
+    // senders waiting for space in a full bounded channel. Only accessed by tasks, never by
+    // interrupt handlers.
+    sender_waiters: Mutex<SenderWaiters>,
+    senders: AtomicUsize,
+    // set when the receiver was dropped or closed
+    closed: AtomicBool,
+}
+
+// Every waiting `Send` future has one entry, which it removes once it completes or is dropped.
+struct SenderWaiters {
+    waiters: VecDeque<(u64, Waker)>,
+    next_id: u64,
+}
+
+impl<T> Chan<T> {
+    fn push(&self, value: T) -> Result<(), T> {
+        match &self.queue {
+            Queue::Bounded(queue) => queue.push(value).map_err(|err| err.0),
+            Queue::Unbounded(queue) => {
+                queue.push(value);
+                Ok(())
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<T> {
+        match &self.queue {
+            Queue::Bounded(queue) => queue.pop().ok(),
+            Queue::Unbounded(queue) => queue.pop().ok(),
+        }
+    }
+
+    fn wake_senders(&self) {
+        for (_, waker) in &self.sender_waiters.lock().waiters {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+// Error returned by `send` when the receiver is gone. Contains the value that was not sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    // the bounded channel is full
+    Full(T),
+    // the receiver is gone
+    Closed(T),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    // no value is available right now
+    Empty,
+    // all senders are gone and the channel is empty
+    Disconnected,
+}
+
+pub struct Sender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Sender<T> {
+    // Sends `value`, waiting for space if the channel is bounded and full.
+    pub fn send(&self, value: T) -> Send<'_, T> {
+        Send {
+            sender: self,
+            value: Some(value),
+            id: None,
+        }
+    }
+
+    // Sends `value` if there is space, without waiting.
+    //
+    // Can be called by interrupt handlers on bounded channels, it does not block or allocate.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        if self.is_closed() {
+            return Err(TrySendError::Closed(value));
+        }
+        self.chan.push(value).map_err(TrySendError::Full)?;
+        self.chan.receiver_waker.wake();
+        Ok(())
+    }
+
+    // Returns whether the receiver is gone.
+    pub fn is_closed(&self) -> bool {
+        self.chan.closed.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.chan.senders.fetch_add(1, Ordering::Relaxed);
+        Sender {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.chan.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // the last sender is gone, the receiver must see the channel is closed
+            self.chan.receiver_waker.wake();
+        }
+    }
+}
+
+// Future returned by `Sender::send`.
+pub struct Send<'a, T> {
+    sender: &'a Sender<T>,
+    value: Option<T>,
+    // set once this future waits for space
+    id: Option<u64>,
+}
+
+impl<T> Send<'_, T> {
+    fn register(&mut self, waker: &Waker) {
+        let mut senders = self.sender.chan.sender_waiters.lock();
+        if let Some(id) = self.id {
+            if let Some((_, registered)) = senders.waiters.iter_mut().find(|(i, _)| *i == id) {
+                if !registered.will_wake(waker) {
+                    *registered = waker.clone();
+                }
+                return;
+            }
+        }
+        let id = senders.next_id;
+        senders.next_id += 1;
+        senders.waiters.push_back((id, waker.clone()));
+        self.id = Some(id);
+    }
+
+    fn unregister(&mut self) {
+        if let Some(id) = self.id.take() {
+            let mut senders = self.sender.chan.sender_waiters.lock();
+            if let Some(index) = senders.waiters.iter().position(|(i, _)| *i == id) {
+                senders.waiters.remove(index);
+            }
+        }
+    }
+}
+
+impl<T> Drop for Send<'_, T> {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+// the value is never pinned, it is moved into the channel
+impl<T> Unpin for Send<'_, T> {}
+
+impl<T> Future for Send<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let value = this.value.take().expect("Send polled after completion");
+        let value = match this.sender.try_send(value) {
+            Ok(()) => {
+                this.unregister();
+                return Poll::Ready(Ok(()));
+            }
+            Err(TrySendError::Closed(value)) => {
+                this.unregister();
+                return Poll::Ready(Err(SendError(value)));
+            }
+            Err(TrySendError::Full(value)) => value,
+        };
+
+        this.register(cx.waker());
+        // the receiver might have made space before the waker was registered
+        match this.sender.try_send(value) {
+            Ok(()) => {
+                this.unregister();
+                Poll::Ready(Ok(()))
+            }
+            Err(TrySendError::Closed(value)) => {
+                this.unregister();
+                Poll::Ready(Err(SendError(value)))
+            }
+            Err(TrySendError::Full(value)) => {
+                this.value = Some(value);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Receiver<T> {
+    // Receives the next value. Returns `None` once all senders are gone and the channel is empty.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv { receiver: self }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if let Some(value) = self.chan.pop() {
+            self.chan.wake_senders();
+            return Ok(value);
+        }
+        if self.chan.senders.load(Ordering::Acquire) == 0 {
+            // a value might have been sent just before the last sender was dropped
+            return self.chan.pop().ok_or(TryRecvError::Disconnected);
+        }
+        Err(TryRecvError::Empty)
+    }
+
+    // Closes the channel, so further sends fail. The values already sent can still be received.
+    pub fn close(&mut self) {
+        self.chan.closed.store(true, Ordering::Release);
+        self.chan.wake_senders();
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        if budget::poll_proceed(cx).is_pending() {
+            return Poll::Pending;
+        }
+        match self.try_recv() {
+            Ok(value) => return Poll::Ready(Some(value)),
+            Err(TryRecvError::Disconnected) => return Poll::Ready(None),
+            Err(TryRecvError::Empty) => {}
+        }
+
+        self.chan.receiver_waker.register(cx.waker());
+        match self.try_recv() {
+            Ok(value) => {
+                self.chan.receiver_waker.take();
+                Poll::Ready(Some(value))
+            }
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+// Future returned by `Receiver::recv`.
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T> Future for Recv<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.receiver.poll_recv(cx)
+    }
+}
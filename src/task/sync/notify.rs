@@ -0,0 +1,180 @@
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use alloc::collections::VecDeque;
+use x86_64::instructions::interrupts;
+
+// Wakes up tasks waiting for an event. If `notify_one` is called while no task waits, the
+// notification is stored and the next `notified().await` completes right away.
+//
+// The state is only locked with interrupts disabled, so interrupt handlers can call
+// `notify_one` and `notify_waiters`.
+pub struct Notify {
+    state: spin::Mutex<State>,
+}
+
+struct State {
+    // a `notify_one` happened while nobody was waiting
+    permit: bool,
+    waiters: VecDeque<Waiter>,
+    next_id: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Notification {
+    None,
+    One,
+    All,
+}
+
+struct Waiter {
+    id: u64,
+    notification: Notification,
+    waker: Waker,
+}
+
+impl State {
+    fn notify_one(&mut self) {
+        match self
+            .waiters
+            .iter_mut()
+            .find(|w| w.notification == Notification::None)
+        {
+            Some(waiter) => {
+                waiter.notification = Notification::One;
+                waiter.waker.wake_by_ref();
+            }
+            None => self.permit = true,
+        }
+    }
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Notify {
+            state: spin::Mutex::new(State {
+                permit: false,
+                waiters: VecDeque::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    // Wakes the task that waits the longest, or stores the notification if none is waiting.
+    //
+    // Does not block or allocate.
+    pub fn notify_one(&self) {
+        interrupts::without_interrupts(|| self.state.lock().notify_one());
+    }
+
+    // Wakes all tasks that are currently waiting. Nothing is stored for later waiters.
+    //
+    // Does not block or allocate.
+    pub fn notify_waiters(&self) {
+        interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+            for waiter in state
+                .waiters
+                .iter_mut()
+                .filter(|w| w.notification == Notification::None)
+            {
+                waiter.notification = Notification::All;
+                waiter.waker.wake_by_ref();
+            }
+        });
+    }
+
+    // Waits for a notification.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            id: None,
+        }
+    }
+}
+
+// Future returned by `Notify::notified`.
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    // set once this future waits in the queue
+    id: Option<u64>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let id = self.id;
+        let result = interrupts::without_interrupts(|| {
+            let mut state = self.notify.state.lock();
+            let id = match id {
+                Some(id) => id,
+                None => {
+                    if state.permit {
+                        state.permit = false;
+                        return Ok(());
+                    }
+                    let id = state.next_id;
+                    state.next_id += 1;
+                    state.waiters.push_back(Waiter {
+                        id,
+                        notification: Notification::None,
+                        waker: cx.waker().clone(),
+                    });
+                    return Err(Some(id));
+                }
+            };
+
+            let index = state
+                .waiters
+                .iter()
+                .position(|w| w.id == id)
+                .expect("notify waiter vanished");
+            if state.waiters[index].notification != Notification::None {
+                state.waiters.remove(index);
+                return Ok(());
+            }
+            if !state.waiters[index].waker.will_wake(cx.waker()) {
+                state.waiters[index].waker = cx.waker().clone();
+            }
+            Err(None)
+        });
+
+        match result {
+            Ok(()) => {
+                self.id = None;
+                Poll::Ready(())
+            }
+            Err(new_id) => {
+                if new_id.is_some() {
+                    self.id = new_id;
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let id = match self.id {
+            Some(id) => id,
+            None => return,
+        };
+        let waiter = interrupts::without_interrupts(|| {
+            let mut state = self.notify.state.lock();
+            let index = state.waiters.iter().position(|w| w.id == id)?;
+            let waiter = state.waiters.remove(index)?;
+            // a `notify_one` meant for this waiter must not get lost
+            if waiter.notification == Notification::One {
+                state.notify_one();
+            }
+            Some(waiter)
+        });
+        // drop the waker with interrupts enabled again
+        drop(waiter);
+    }
+}
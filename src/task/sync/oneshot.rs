@@ -0,0 +1,141 @@
+use core::{
+    cell::UnsafeCell,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU8, Ordering},
+    task::{Context, Poll},
+};
+
+use alloc::sync::Arc;
+use futures_util::task::AtomicWaker;
+
+// Channel for sending a single value, e.g. the result of a request. Sending never blocks or
+// allocates, so it can be done from an interrupt handler.
+
+// bits of `Inner::state`
+const VALUE_SENT: u8 = 1 << 0;
+const SENDER_DROPPED: u8 = 1 << 1;
+const RECEIVER_CLOSED: u8 = 1 << 2;
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(Inner {
+        state: AtomicU8::new(0),
+        value: UnsafeCell::new(None),
+        waker: AtomicWaker::new(),
+    });
+    (
+        Sender {
+            inner: inner.clone(),
+        },
+        Receiver { inner },
+    )
+}
+
+struct Inner<T> {
+    state: AtomicU8,
+    // written by the sender before it sets `VALUE_SENT`, only read by the receiver afterwards
+    value: UnsafeCell<Option<T>>,
+    waker: AtomicWaker,
+}
+
+// the access to `value` is synchronized through `state`
+unsafe impl<T: Send> Sync for Inner<T> {}
+
+// Error returned by the `Receiver` when the sender was dropped without sending a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    // the value was not sent yet
+    Empty,
+    // the sender was dropped without sending a value
+    Closed,
+}
+
+pub struct Sender<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Sender<T> {
+    // Sends `value`, or returns it if the receiver is gone.
+    //
+    // Can be called by interrupt handlers, it does not block or allocate.
+    pub fn send(self, value: T) -> Result<(), T> {
+        if self.is_closed() {
+            return Err(value);
+        }
+        // this is okay because only the sender writes the value, and the receiver does not read
+        // it before `VALUE_SENT` is set
+        unsafe { *self.inner.value.get() = Some(value) };
+        self.inner.state.fetch_or(VALUE_SENT, Ordering::AcqRel);
+        self.inner.waker.wake();
+        Ok(())
+    }
+
+    // Returns whether the receiver is gone.
+    pub fn is_closed(&self) -> bool {
+        self.inner.state.load(Ordering::Acquire) & RECEIVER_CLOSED != 0
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.inner.state.fetch_or(SENDER_DROPPED, Ordering::AcqRel);
+        self.inner.waker.wake();
+    }
+}
+
+// Awaiting the receiver yields the sent value.
+pub struct Receiver<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let state = self.inner.state.load(Ordering::Acquire);
+        if state & VALUE_SENT != 0 {
+            // this is okay because the sender does not touch the value after setting `VALUE_SENT`
+            if let Some(value) = unsafe { (*self.inner.value.get()).take() } {
+                return Ok(value);
+            }
+            // the value was already received
+            return Err(TryRecvError::Closed);
+        }
+        if state & SENDER_DROPPED != 0 {
+            return Err(TryRecvError::Closed);
+        }
+        Err(TryRecvError::Empty)
+    }
+
+    // Prevents the sender from sending a value.
+    pub fn close(&mut self) {
+        self.inner.state.fetch_or(RECEIVER_CLOSED, Ordering::AcqRel);
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        match this.try_recv() {
+            Ok(value) => return Poll::Ready(Ok(value)),
+            Err(TryRecvError::Closed) => return Poll::Ready(Err(RecvError)),
+            Err(TryRecvError::Empty) => {}
+        }
+
+        this.inner.waker.register(cx.waker());
+        match this.try_recv() {
+            Ok(value) => Poll::Ready(Ok(value)),
+            Err(TryRecvError::Closed) => Poll::Ready(Err(RecvError)),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
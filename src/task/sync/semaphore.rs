@@ -0,0 +1,200 @@
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use alloc::collections::VecDeque;
+
+// Counting semaphore whose waiters are served in FIFO order: a task that waits for many permits
+// is not overtaken by later tasks that need fewer. This is what `Mutex` and `RwLock` are built on.
+pub struct Semaphore {
+    state: spin::Mutex<State>,
+}
+
+struct State {
+    permits: usize,
+    waiters: VecDeque<Waiter>,
+    next_id: u64,
+}
+
+struct Waiter {
+    id: u64,
+    needed: usize,
+    // the permits were handed to this waiter by `release`, it only has to pick them up
+    granted: bool,
+    waker: Waker,
+}
+
+impl State {
+    // Hands the available permits to the waiters at the front of the queue.
+    fn grant(&mut self) {
+        for waiter in self.waiters.iter_mut().filter(|w| !w.granted) {
+            if waiter.needed > self.permits {
+                // later waiters must not overtake this one
+                break;
+            }
+            self.permits -= waiter.needed;
+            waiter.granted = true;
+            waiter.waker.wake_by_ref();
+        }
+    }
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            state: spin::Mutex::new(State {
+                permits,
+                waiters: VecDeque::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    // Adds `n` new permits, waking the waiters they satisfy.
+    pub fn add_permits(&self, n: usize) {
+        let mut state = self.state.lock();
+        state.permits += n;
+        state.grant();
+    }
+
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    // Waits until `n` permits are available and takes them.
+    pub fn acquire_many(&self, n: usize) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            needed: n,
+            id: None,
+        }
+    }
+
+    // Takes a permit if one is available and nobody is waiting for one.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.try_acquire_many(1)
+    }
+
+    pub fn try_acquire_many(&self, n: usize) -> Option<SemaphorePermit<'_>> {
+        let mut state = self.state.lock();
+        if state.waiters.is_empty() && state.permits >= n {
+            state.permits -= n;
+            Some(SemaphorePermit {
+                semaphore: self,
+                permits: n,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+// Permits taken from a `Semaphore`. They are given back when this is dropped.
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    // Drops the permits without giving them back to the semaphore.
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.add_permits(self.permits);
+        }
+    }
+}
+
+// Future returned by `Semaphore::acquire` and `Semaphore::acquire_many`.
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    needed: usize,
+    // set once this future waits in the queue
+    id: Option<u64>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<SemaphorePermit<'a>> {
+        let semaphore = self.semaphore;
+        let needed = self.needed;
+        let acquired = {
+            let mut state = semaphore.state.lock();
+            match self.id {
+                None if state.waiters.is_empty() && state.permits >= needed => {
+                    state.permits -= needed;
+                    true
+                }
+                None => {
+                    let id = state.next_id;
+                    state.next_id += 1;
+                    state.waiters.push_back(Waiter {
+                        id,
+                        needed,
+                        granted: false,
+                        waker: cx.waker().clone(),
+                    });
+                    self.id = Some(id);
+                    false
+                }
+                Some(id) => {
+                    let index = state
+                        .waiters
+                        .iter()
+                        .position(|w| w.id == id)
+                        .expect("semaphore waiter vanished");
+                    if state.waiters[index].granted {
+                        state.waiters.remove(index);
+                        self.id = None;
+                        true
+                    } else {
+                        if !state.waiters[index].waker.will_wake(cx.waker()) {
+                            state.waiters[index].waker = cx.waker().clone();
+                        }
+                        false
+                    }
+                }
+            }
+        };
+
+        if acquired {
+            Poll::Ready(SemaphorePermit {
+                semaphore,
+                permits: needed,
+            })
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        let id = match self.id {
+            Some(id) => id,
+            None => return,
+        };
+        let mut state = self.semaphore.state.lock();
+        if let Some(index) = state.waiters.iter().position(|w| w.id == id) {
+            let waiter = state.waiters.remove(index).unwrap();
+            if waiter.granted {
+                // the permits were never picked up, give them back
+                state.permits += waiter.needed;
+            }
+            // this waiter might have blocked the ones behind it
+            state.grant();
+        }
+    }
+}
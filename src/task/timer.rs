@@ -0,0 +1,252 @@
+use core::{
+    future::Future,
+    mem,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use alloc::collections::BTreeMap;
+use futures_util::stream::Stream;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::time::{self, hpet, Duration, Instant};
+
+struct Timer {
+    waker: Waker,
+    // set by the interrupt handler once the deadline passed, so the waker is only woken once
+    fired: bool,
+}
+
+// All pending timers, ordered by their deadline (the id tells apart timers with the same one).
+//
+// The timer interrupt handler only wakes the wakers by reference and marks the timers as fired, so
+// it never allocates or frees memory. The entries are inserted and removed by the timer futures
+// themselves, also when they are dropped or reset before they fire.
+type TimerQueue = BTreeMap<(Instant, u64), Timer>;
+
+lazy_static! {
+    static ref TIMERS: Mutex<TimerQueue> = Mutex::new(BTreeMap::new());
+}
+
+// The earliest deadline in `TIMERS` that did not fire yet (in nanoseconds since boot), so the
+// interrupt handler does not need to take the lock on every tick.
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(0);
+
+// Called by the timer interrupt handler
+//
+// Must not block or allocate
+pub(crate) fn wake_expired_timers() {
+    let now = time::now();
+    if now.as_uptime_nanos() < NEXT_DEADLINE.load(Ordering::Relaxed) {
+        return;
+    }
+    // the lock is only held with interrupts disabled, so it can only be taken by another CPU; in
+    // that case the timers are processed on the next tick
+    if let Some(mut timers) = TIMERS.try_lock() {
+        for (&(deadline, _), timer) in timers.iter_mut() {
+            if deadline > now {
+                break;
+            }
+            if !timer.fired {
+                timer.waker.wake_by_ref();
+                timer.fired = true;
+            }
+        }
+        update_next_deadline(&timers);
+    }
+}
+
+// Must be called with the `TIMERS` lock held whenever the earliest deadline might have changed.
+fn update_next_deadline(timers: &TimerQueue) {
+    // the fired timers stay until their futures are polled again
+    let next = match timers.iter().find(|(_, timer)| !timer.fired) {
+        Some((&(deadline, _), _)) => {
+            // fire exactly at the deadline instead of at the next tick, if the HPET is available
+            hpet::arm_event_timer(deadline);
+            deadline.as_uptime_nanos()
+        }
+        None => u64::MAX,
+    };
+    NEXT_DEADLINE.store(next, Ordering::Relaxed);
+}
+
+// Waits until `duration` has passed. A duration too long to represent sleeps forever.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(deadline_after(time::now(), duration))
+}
+
+// `start + duration`, or the latest representable instant if that overflows
+fn deadline_after(start: Instant, duration: Duration) -> Instant {
+    start
+        .checked_add(duration)
+        .unwrap_or(Instant::from_uptime_nanos(u64::MAX))
+}
+
+// Waits until `deadline` is reached.
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep { deadline, id: None }
+}
+
+// Future returned by `sleep` and `sleep_until`.
+//
+// The timer only gets registered on the first `poll`, so creating a `Sleep` that is never awaited
+// costs nothing. The resolution is one tick of the timer interrupt, unless the HPET event timer is
+// enabled.
+pub struct Sleep {
+    deadline: Instant,
+    id: Option<u64>,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    // Changes the deadline of this timer, even if it already expired.
+    pub fn reset(&mut self, deadline: Instant) {
+        self.unregister();
+        self.deadline = deadline;
+    }
+
+    fn unregister(&mut self) {
+        if let Some(id) = self.id.take() {
+            let deadline = self.deadline;
+            let timer = interrupts::without_interrupts(|| {
+                let mut timers = TIMERS.lock();
+                let timer = timers.remove(&(deadline, id));
+                update_next_deadline(&timers);
+                timer
+            });
+            // drop the waker with interrupts enabled again
+            drop(timer);
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if time::now() >= self.deadline {
+            self.unregister();
+            return Poll::Ready(());
+        }
+
+        let deadline = self.deadline;
+        let id = *self
+            .id
+            .get_or_insert_with(|| NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed));
+        let old_waker = interrupts::without_interrupts(|| {
+            let mut timers = TIMERS.lock();
+            match timers.get_mut(&(deadline, id)) {
+                Some(timer) if timer.waker.will_wake(cx.waker()) => None,
+                Some(timer) => Some(mem::replace(&mut timer.waker, cx.waker().clone())),
+                None => {
+                    let timer = Timer {
+                        waker: cx.waker().clone(),
+                        fired: false,
+                    };
+                    timers.insert((deadline, id), timer);
+                    update_next_deadline(&timers);
+                    None
+                }
+            }
+        });
+        drop(old_waker);
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+// Error returned by `timeout` when the deadline passed before the future completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+// Runs `future` but gives up after `duration`. The future is dropped when the time runs out.
+pub fn timeout<F: Future>(future: F, duration: Duration) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration),
+    }
+}
+
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // this is okay because `future` is never moved out of `self`, and `Sleep` is `Unpin`
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut this.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// Creates a stream that yields every `period`, starting one `period` from now.
+pub fn interval(period: Duration) -> Interval {
+    assert!(period > Duration::ZERO, "interval period must be non-zero");
+    Interval {
+        period,
+        sleep: sleep(period),
+    }
+}
+
+// Stream returned by `interval`, yielding the instant each tick was scheduled for.
+//
+// If a tick is missed (because the task was not polled in time), the missed ticks are skipped
+// instead of being delivered in a burst.
+pub struct Interval {
+    period: Duration,
+    sleep: Sleep,
+}
+
+impl Interval {
+    // Waits for the next tick.
+    pub async fn tick(&mut self) -> Instant {
+        futures_util::future::poll_fn(|cx| self.poll_tick(cx)).await
+    }
+
+    pub fn poll_tick(&mut self, cx: &mut Context) -> Poll<Instant> {
+        match Pin::new(&mut self.sleep).poll(cx) {
+            Poll::Ready(()) => {
+                let scheduled = self.sleep.deadline();
+                let now = time::now();
+                let mut next = deadline_after(scheduled, self.period);
+                if next <= now {
+                    next = deadline_after(now, self.period);
+                }
+                self.sleep.reset(next);
+                Poll::Ready(scheduled)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Instant>> {
+        self.get_mut().poll_tick(cx).map(Some)
+    }
+}
@@ -0,0 +1,370 @@
+// Preemptive kernel threads. Every thread has its own stack; the timer interrupt switches between
+// the ready threads round-robin, so a thread stuck in a loop cannot hang the kernel. The context
+// that calls `init` (i.e. `kernel_main`) becomes the first thread, so the async executor can keep
+// running on it next to the other threads.
+
+mod switch;
+
+use alloc::{boxed::Box, collections::BTreeMap, collections::VecDeque, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+
+use crate::time::{self, Duration, Instant};
+use crate::{memory, percpu};
+
+// size of the stack of every thread, in pages. The page below it is left unmapped, so a thread
+// overflowing its stack causes a double fault instead of corrupting memory.
+const STACK_PAGES: u64 = 4;
+// number of timer ticks a thread runs before the next ready thread gets the CPU
+const TIME_SLICE_TICKS: u64 = 1;
+
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+// id of the running thread, so it can be read without taking the scheduler lock
+static CURRENT: AtomicU64 = AtomicU64::new(0);
+// ticks left in the time slice of the current thread
+static SLICE_LEFT: AtomicU64 = AtomicU64::new(TIME_SLICE_TICKS);
+// ends of the stacks of the exited threads. Their frames are not given back to the frame allocator
+// (which cannot take them), so the next threads reuse them.
+static FREE_STACKS: Mutex<Vec<VirtAddr>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ready,
+    Running,
+    Sleeping(Instant),
+    // waiting for the given thread to exit
+    Joining(ThreadId),
+    Finished,
+}
+
+struct Thread {
+    state: State,
+    // saved stack pointer while the thread is not running, written by `switch::switch_context`
+    rsp: u64,
+    // end of the stack, `None` for the initial thread, which runs on the boot stack
+    stack: Option<VirtAddr>,
+    // taken by the trampoline when the thread starts
+    entry: Option<Box<dyn FnOnce() + Send>>,
+}
+
+struct Scheduler {
+    // threads are boxed so the saved `rsp` keeps its address while the map changes
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    // runnable threads, except the current one. Always has room for every thread, so the timer
+    // interrupt never has to allocate.
+    ready: VecDeque<ThreadId>,
+    current: ThreadId,
+    idle: ThreadId,
+    next_id: u64,
+}
+
+impl Scheduler {
+    fn current(&mut self) -> &mut Thread {
+        self.threads.get_mut(&self.current).unwrap()
+    }
+
+    fn add(&mut self, thread: Thread) -> ThreadId {
+        let id = ThreadId(self.next_id);
+        self.next_id += 1;
+        self.threads.insert(id, Box::new(thread));
+        self.ready.reserve(self.threads.len());
+        id
+    }
+
+    // Moves the sleeping threads whose deadline passed to the ready queue.
+    fn wake_sleepers(&mut self) {
+        let now = time::now();
+        for (&id, thread) in self.threads.iter_mut() {
+            if let State::Sleeping(deadline) = thread.state {
+                if deadline <= now {
+                    thread.state = State::Ready;
+                    self.ready.push_back(id);
+                }
+            }
+        }
+    }
+
+    // Removes an exited thread, except the current one whose stack is still in use.
+    fn take_finished(&mut self) -> Option<Box<Thread>> {
+        let current = self.current;
+        let id = self
+            .threads
+            .iter()
+            .find(|(&id, thread)| id != current && thread.state == State::Finished)
+            .map(|(&id, _)| id)?;
+        self.threads.remove(&id)
+    }
+
+    // Picks the next thread to run and marks it as current. Returns the location to save the
+    // current stack pointer in and the stack pointer to switch to, or `None` if the current thread
+    // keeps running.
+    //
+    // The current thread must already have its new state; if it is still `Running`, it is put at
+    // the end of the ready queue.
+    fn schedule(&mut self) -> Option<(*mut u64, u64)> {
+        self.wake_sleepers();
+        let current = self.current;
+        let still_runnable = self.current().state == State::Running;
+        let next = match self.ready.pop_front() {
+            Some(next) => next,
+            None if still_runnable => return None,
+            None => self.idle,
+        };
+        if next == current {
+            return None;
+        }
+        if still_runnable && current != self.idle {
+            self.current().state = State::Ready;
+            self.ready.push_back(current);
+        }
+
+        let old_rsp = &mut self.current().rsp as *mut u64;
+        self.current = next;
+        CURRENT.store(next.0, Ordering::Relaxed);
+        let next_thread = self.current();
+        next_thread.state = State::Running;
+        SLICE_LEFT.store(TIME_SLICE_TICKS, Ordering::Relaxed);
+        Some((old_rsp, next_thread.rsp))
+    }
+}
+
+// Turns the current context into the first thread and creates the idle thread. Must be called
+// after the heap is initialized and `memory::install`.
+pub fn init() {
+    let mut scheduler = Scheduler {
+        threads: BTreeMap::new(),
+        ready: VecDeque::new(),
+        current: ThreadId(0),
+        idle: ThreadId(0),
+        next_id: 0,
+    };
+    scheduler.current = scheduler.add(Thread {
+        state: State::Running,
+        rsp: 0,
+        stack: None,
+        entry: None,
+    });
+    // the idle thread only runs when nothing else is ready, it is never queued. It still needs a
+    // full stack, the interrupt handlers run on it.
+    scheduler.idle = scheduler.add(new_thread(Box::new(|| {
+        crate::hlt_loop();
+    })));
+
+    interrupts::without_interrupts(|| *SCHEDULER.lock() = Some(scheduler));
+    ENABLED.store(true, Ordering::Release);
+}
+
+fn new_thread(entry: Box<dyn FnOnce() + Send>) -> Thread {
+    let stack = FREE_STACKS.lock().pop().unwrap_or_else(|| {
+        memory::with_kernel_memory(|mapper, frame_allocator| {
+            memory::map_stack(STACK_PAGES, mapper, frame_allocator)
+        })
+        .expect("no memory left for a thread stack")
+    });
+    // the stack is mapped and no other thread uses it
+    let rsp = unsafe { switch::prepare_stack(stack) };
+    Thread {
+        state: State::Ready,
+        rsp,
+        stack: Some(stack),
+        entry: Some(entry),
+    }
+}
+
+// Runs `f` and switches to the next thread, with interrupts disabled and the scheduler lock
+// released before switching.
+//
+// The application processors do not run threads, the scheduler's current thread belongs to the
+// BSP. There `f` is not run and this only spins once, so `sleep` and `join` busy-wait instead.
+fn reschedule(f: impl FnOnce(&mut Scheduler)) {
+    if !percpu::is_bsp() {
+        core::hint::spin_loop();
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let switch = {
+            let mut scheduler = SCHEDULER.lock();
+            let scheduler = scheduler.as_mut().expect("thread::init was not called");
+            f(scheduler);
+            scheduler.schedule()
+        };
+        if let Some((old_rsp, new_rsp)) = switch {
+            // the threads are boxed and the current one is never reaped, so `old_rsp` stays valid
+            unsafe { switch::switch_context(old_rsp, new_rsp) };
+        }
+    });
+    reap();
+}
+
+// Frees the exited threads and puts their stacks back for reuse. The current thread's stack is
+// still in use, so it is only freed by a later call.
+//
+// The memory is freed outside of the scheduler lock and with interrupts enabled again, so the
+// scheduler never waits for the allocator lock of a preempted thread.
+fn reap() {
+    while let Some(thread) = interrupts::without_interrupts(|| {
+        SCHEDULER.lock().as_mut().and_then(Scheduler::take_finished)
+    }) {
+        if let Some(stack) = thread.stack {
+            FREE_STACKS.lock().push(stack);
+        }
+    }
+}
+
+// Called by the timer interrupt handler after the EOI was sent
+//
+// Must not block or allocate
+pub(crate) fn preempt() {
+    if !ENABLED.load(Ordering::Acquire) || SLICE_LEFT.fetch_sub(1, Ordering::Relaxed) > 1 {
+        return;
+    }
+    // the current thread gets a new slice if nothing else is ready
+    SLICE_LEFT.store(TIME_SLICE_TICKS, Ordering::Relaxed);
+    let switch = match SCHEDULER.try_lock() {
+        Some(mut scheduler) => scheduler.as_mut().and_then(|s| s.schedule()),
+        None => None,
+    };
+    if let Some((old_rsp, new_rsp)) = switch {
+        // the interrupted thread continues here (and returns from the interrupt) once it is
+        // scheduled again
+        unsafe { switch::switch_context(old_rsp, new_rsp) };
+    }
+}
+
+// Entry point of every new thread, `switch::prepare_stack` makes `switch_context` return here.
+extern "C" fn thread_start() -> ! {
+    let entry = {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.as_mut().unwrap().current().entry.take()
+    };
+    // a new thread is entered with interrupts disabled, like every context switch
+    interrupts::enable();
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit();
+}
+
+// Ends the current thread, waking the threads joining it.
+fn exit() -> ! {
+    reschedule(|scheduler| {
+        let current = scheduler.current;
+        scheduler.current().state = State::Finished;
+        for (&id, thread) in scheduler.threads.iter_mut() {
+            if thread.state == State::Joining(current) {
+                thread.state = State::Ready;
+                scheduler.ready.push_back(id);
+            }
+        }
+    });
+    unreachable!("finished thread was scheduled again");
+}
+
+// Starts a new thread running `f`. Can be called from any thread, but not from an interrupt
+// handler since it allocates.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let result = Arc::new(Mutex::new(None));
+    let thread_result = result.clone();
+    let thread = new_thread(Box::new(move || {
+        let output = f();
+        *thread_result.lock() = Some(output);
+    }));
+    let id = interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("thread::init was not called");
+        let id = scheduler.add(thread);
+        scheduler.ready.push_back(id);
+        id
+    });
+    JoinHandle { id, result }
+}
+
+// Returns the id of the current thread.
+pub fn current() -> ThreadId {
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_ref()
+            .expect("thread::init was not called")
+            .current
+    })
+}
+
+// Returns the id of the current thread without taking the scheduler lock, so it can be used in
+// interrupt handlers. `None` before `init` and on the application processors, which do not run
+// threads.
+pub(crate) fn current_unlocked() -> Option<ThreadId> {
+    if !ENABLED.load(Ordering::Acquire) || !percpu::is_bsp() {
+        return None;
+    }
+    Some(ThreadId(CURRENT.load(Ordering::Relaxed)))
+}
+
+// Gives the rest of the time slice to the next ready thread.
+pub fn yield_now() {
+    reschedule(|_| {});
+}
+
+// Blocks the current thread for at least `duration`. The other threads run in the meantime.
+pub fn sleep(duration: Duration) {
+    let deadline = time::now() + duration;
+    while time::now() < deadline {
+        reschedule(|scheduler| scheduler.current().state = State::Sleeping(deadline));
+    }
+}
+
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn thread(&self) -> ThreadId {
+        self.id
+    }
+
+    // Blocks until the thread exits and returns its result.
+    pub fn join(self) -> T {
+        loop {
+            if let Some(output) = self.result.lock().take() {
+                return output;
+            }
+            let id = self.id;
+            reschedule(|scheduler| {
+                let finished = scheduler
+                    .threads
+                    .get(&id)
+                    .map_or(true, |thread| thread.state == State::Finished);
+                // the result is stored right before the thread exits, so this only waits for a
+                // thread that is still running
+                if !finished {
+                    scheduler.current().state = State::Joining(id);
+                }
+            });
+        }
+    }
+}
+
+// Returns the ids of all threads, including the idle thread.
+pub fn threads() -> Vec<ThreadId> {
+    interrupts::without_interrupts(|| match SCHEDULER.lock().as_ref() {
+        Some(scheduler) => scheduler.threads.keys().copied().collect(),
+        None => Vec::new(),
+    })
+}
@@ -0,0 +1,57 @@
+use core::arch::global_asm;
+use x86_64::VirtAddr;
+
+// Saves the callee-saved registers of the current thread on its stack, stores its stack pointer
+// in `*old_rsp`, then loads `new_rsp` and restores the registers of the other thread from there.
+// The caller-saved registers are saved by the compiler around the call, and the kernel does not
+// use SSE, so this is the whole context.
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+);
+
+extern "C" {
+    // Must be called with interrupts disabled.
+    pub(super) fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+}
+
+// Lays out a new stack as if `switch_context` had been called on it from `thread_start`, so the
+// first switch to it "returns" into `thread_start`. Returns the initial stack pointer.
+//
+// This function is unsafe because the caller must guarantee that the stack below `stack_end` is
+// mapped and not in use.
+pub(super) unsafe fn prepare_stack(stack_end: VirtAddr) -> u64 {
+    let top = stack_end.as_u64() & !0xf;
+    let frame: [u64; 8] = [
+        // r15, r14, r13, r12, rbx, rbp
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+        // popped by `ret`
+        super::thread_start as extern "C" fn() -> ! as usize as u64,
+        // fake return address of `thread_start` (which never returns), so the stack is aligned
+        // like after a `call`
+        0,
+    ];
+    let rsp = top - 8 * frame.len() as u64;
+    core::ptr::copy_nonoverlapping(frame.as_ptr(), rsp as *mut u64, frame.len());
+    rsp
+}
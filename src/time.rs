@@ -0,0 +1,215 @@
+// This module keeps track of the time since boot. The timer interrupt (raised by the PIT, or by
+// the local APIC timer once the APIC is enabled) increments a monotonic tick counter, from which
+// `now` derives an `Instant`. Once the HPET is enabled, its main counter is used instead.
+
+pub mod hpet;
+pub mod pit;
+pub mod tsc;
+
+use core::{
+    ops::{Add, AddAssign, Sub},
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+pub use core::time::Duration;
+
+use crate::apic;
+use crate::interrupts::InterruptsIndex;
+use crate::rtc::{self, DateTime};
+
+// Timer interrupts per second, unless changed with `set_frequency`.
+pub const DEFAULT_FREQUENCY: u32 = 100;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static UPTIME_NANOS: AtomicU64 = AtomicU64::new(0);
+static NANOS_PER_TICK: AtomicU64 = AtomicU64::new(0);
+static FREQUENCY: AtomicU32 = AtomicU32::new(0);
+// UNIX time in nanoseconds at uptime zero, 0 until `init_wall_clock` read the RTC
+static BOOT_UNIX_NANOS: AtomicU64 = AtomicU64::new(0);
+
+// Programs the PIT to the default frequency and calibrates the TSC. Must be called before
+// interrupts are enabled.
+pub fn init() {
+    set_frequency(DEFAULT_FREQUENCY);
+    tsc::init();
+}
+
+// Changes the timer interrupt frequency. The uptime stays monotonic, only the length of the ticks
+// following this call changes. A frequency of 0 is taken as 1.
+pub fn set_frequency(frequency: u32) {
+    let frequency = frequency.max(1);
+    if apic::is_enabled() {
+        let nanos_per_tick = apic::lapic::start_timer(InterruptsIndex::Timer.as_u8(), frequency);
+        set_tick_period(frequency, nanos_per_tick);
+    } else {
+        let actual = pit::set_frequency(frequency);
+        set_tick_period(actual, NANOS_PER_SECOND / actual as u64);
+    }
+}
+
+// Records the frequency and the length of a tick of the timer that drives `tick`. Used by the
+// local APIC timer, which replaces the PIT.
+pub(crate) fn set_tick_period(frequency: u32, nanos_per_tick: u64) {
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+    NANOS_PER_TICK.store(nanos_per_tick, Ordering::Relaxed);
+}
+
+// Called by the timer interrupt handler
+//
+// Must not block or allocate
+pub(crate) fn tick() {
+    UPTIME_NANOS.fetch_add(NANOS_PER_TICK.load(Ordering::Relaxed), Ordering::Relaxed);
+    TICKS.fetch_add(1, Ordering::Release);
+}
+
+// Returns the current timer interrupt frequency in Hz.
+pub fn frequency() -> u32 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+// Returns the number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Acquire)
+}
+
+// Returns the time since the timer was started, with the resolution of one tick (or of the HPET,
+// if it is enabled).
+pub fn uptime() -> Duration {
+    Duration::from_nanos(now().as_uptime_nanos())
+}
+
+// Returns the current point in time.
+pub fn now() -> Instant {
+    Instant::now()
+}
+
+// Returns the current point in time with nanosecond resolution. This uses the TSC if it is
+// invariant, otherwise it falls back to `now`.
+pub fn precise_now() -> Instant {
+    tsc::now().unwrap_or_else(now)
+}
+
+// Reads the date and time from the RTC once, afterwards the wall clock is derived from the uptime.
+// Must be called after `acpi::init`. A date the nanosecond counter cannot represent (past 2554)
+// leaves the wall clock unset.
+pub fn init_wall_clock() {
+    let unix_nanos = match rtc::init().unix_timestamp().checked_mul(NANOS_PER_SECOND) {
+        Some(unix_nanos) => unix_nanos,
+        None => return,
+    };
+    let uptime = now().as_uptime_nanos();
+    BOOT_UNIX_NANOS.store(unix_nanos.saturating_sub(uptime), Ordering::Relaxed);
+}
+
+// Returns the current calendar time, or `None` if `init_wall_clock` was not called.
+pub fn wall_clock() -> Option<WallClock> {
+    match BOOT_UNIX_NANOS.load(Ordering::Relaxed) {
+        0 => None,
+        boot => Some(WallClock {
+            since_epoch: Duration::from_nanos(boot + now().as_uptime_nanos()),
+        }),
+    }
+}
+
+// A calendar time (UTC), returned by `wall_clock`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WallClock {
+    since_epoch: Duration,
+}
+
+impl WallClock {
+    // Returns the time since 1970-01-01 00:00:00 UTC.
+    pub fn since_unix_epoch(&self) -> Duration {
+        self.since_epoch
+    }
+
+    // Returns the number of whole seconds since 1970-01-01 00:00:00 UTC.
+    pub fn unix_timestamp(&self) -> u64 {
+        self.since_epoch.as_secs()
+    }
+
+    pub fn date_time(&self) -> DateTime {
+        DateTime::from_unix_timestamp(self.unix_timestamp())
+    }
+}
+
+// A point in time, measured since boot. Unlike a `Duration`, an `Instant` is only meaningful
+// relative to another `Instant`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    nanos: u64,
+}
+
+impl Instant {
+    pub fn now() -> Self {
+        hpet::now().unwrap_or(Instant {
+            nanos: UPTIME_NANOS.load(Ordering::Relaxed),
+        })
+    }
+
+    // Returns the instant that lies the given duration after boot.
+    pub const fn from_uptime_nanos(nanos: u64) -> Self {
+        Instant { nanos }
+    }
+
+    pub fn as_uptime_nanos(&self) -> u64 {
+        self.nanos
+    }
+
+    // Returns the time that passed between `earlier` and `self`, or zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        Some(Instant {
+            nanos: self.nanos.checked_add(nanos)?,
+        })
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        Some(Instant {
+            nanos: self.nanos.checked_sub(nanos)?,
+        })
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
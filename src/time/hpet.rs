@@ -0,0 +1,220 @@
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Size4KiB};
+use x86_64::PhysAddr;
+
+use super::{tsc, Instant};
+use crate::acpi::{self, AcpiError};
+use crate::apic;
+use crate::interrupts::InterruptsIndex;
+use crate::memory;
+
+// The HPET (High Precision Event Timer) has a main counter that runs at a fixed frequency of at
+// least 10 MHz, and a set of comparators ("timers") that raise an interrupt when the main counter
+// reaches their value. Its registers are memory mapped, the address is found in the ACPI HPET
+// table.
+
+// register offsets
+const CAPABILITIES: usize = 0x000;
+const CONFIGURATION: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0f0;
+const fn timer_configuration(timer: usize) -> usize {
+    0x100 + 0x20 * timer
+}
+const fn timer_comparator(timer: usize) -> usize {
+    0x108 + 0x20 * timer
+}
+
+// bits of the capabilities register
+const COUNTER_64_BIT: u64 = 1 << 13;
+// bits of the configuration register
+const ENABLE: u64 = 1 << 0;
+// bits of the timer configuration registers
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_64_BIT: u64 = 1 << 5;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+
+// the timer used as one-shot event timer
+const EVENT_TIMER: usize = 0;
+
+// virtual address of the registers, 0 until `init` succeeded
+static BASE: AtomicU64 = AtomicU64::new(0);
+// length of a main counter tick in femtoseconds
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+// uptime in nanoseconds when the main counter was started, so the clock continues seamlessly from
+// the tick based one
+static BASE_NANOS: AtomicU64 = AtomicU64::new(0);
+static EVENT_TIMER_ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+pub enum HpetError {
+    // there is no HPET table
+    Acpi(AcpiError),
+    // the registers could not be mapped
+    Map(MapToError<Size4KiB>),
+    // the main counter is only 32 bits wide (it would wrap after a few minutes) or its period is
+    // invalid
+    Unsupported,
+}
+
+impl From<AcpiError> for HpetError {
+    fn from(err: AcpiError) -> Self {
+        HpetError::Acpi(err)
+    }
+}
+
+impl From<MapToError<Size4KiB>> for HpetError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        HpetError::Map(err)
+    }
+}
+
+unsafe fn read(register: usize) -> u64 {
+    let addr = BASE.load(Ordering::Relaxed) as usize + register;
+    ptr::read_volatile(addr as *const u64)
+}
+
+unsafe fn write(register: usize, value: u64) {
+    let addr = BASE.load(Ordering::Relaxed) as usize + register;
+    ptr::write_volatile(addr as *mut u64, value);
+}
+
+// Starts the HPET main counter and makes it the clocksource of `time::now`, recalibrates the TSC
+// against it, and, if the APIC is enabled, sets up timer 0 as one-shot event timer for the async
+// timers in `task::timer`.
+//
+// Must be called after `acpi::init` and `apic::init`.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), HpetError> {
+    let table = acpi::find_table(b"HPET")?;
+    // the registers are described by a "generic address structure" at offset 40, whose address
+    // field is at offset 4
+    let phys = unsafe { acpi::read_phys::<u64>(table + 44u64) };
+    let base = unsafe { memory::map_mmio(PhysAddr::new(phys), 1024, mapper, frame_allocator)? };
+
+    interrupts::without_interrupts(|| {
+        BASE.store(base.as_u64(), Ordering::Relaxed);
+        let capabilities = unsafe { read(CAPABILITIES) };
+        let period = capabilities >> 32;
+        // the specification limits the period to 100ns
+        if capabilities & COUNTER_64_BIT == 0 || period == 0 || period > 100_000_000 {
+            BASE.store(0, Ordering::Relaxed);
+            return Err(HpetError::Unsupported);
+        }
+        let timer_count = ((capabilities >> 8) & 0x1f) as usize + 1;
+
+        unsafe {
+            write(CONFIGURATION, read(CONFIGURATION) & !ENABLE);
+            for timer in 0..timer_count {
+                let config = read(timer_configuration(timer));
+                write(timer_configuration(timer), config & !TIMER_INTERRUPT_ENABLE);
+            }
+            write(MAIN_COUNTER, 0);
+            BASE_NANOS.store(super::now().as_uptime_nanos(), Ordering::Relaxed);
+            PERIOD_FS.store(period, Ordering::Relaxed);
+            write(CONFIGURATION, read(CONFIGURATION) | ENABLE);
+        }
+
+        calibrate_tsc();
+        if apic::is_enabled() {
+            init_event_timer();
+        }
+        Ok(())
+    })
+}
+
+// Measures the TSC frequency over 10ms of the main counter, which is far more precise than the
+// PIT based calibration done at boot.
+fn calibrate_tsc() {
+    if !tsc::is_supported() {
+        return;
+    }
+    let start_counter = counter();
+    let start = tsc::read();
+    while counter_to_nanos(counter() - start_counter) < 10_000_000 {
+        core::hint::spin_loop();
+    }
+    let cycles = tsc::read() - start;
+    let nanos = counter_to_nanos(counter() - start_counter);
+    tsc::set_frequency(cycles * 1_000_000_000 / nanos);
+}
+
+// Routes timer 0 through the I/O APIC to its own vector and puts it in one-shot mode.
+fn init_event_timer() {
+    unsafe {
+        let config = read(timer_configuration(EVENT_TIMER));
+        if config & TIMER_64_BIT == 0 {
+            return;
+        }
+        // the upper half lists the I/O APIC inputs the timer can be connected to; the first 16
+        // belong to the ISA IRQs, so they are skipped
+        let route_capabilities = (config >> 32) as u32;
+        let vector = InterruptsIndex::Hpet.as_u8();
+        let gsi = match (16..32)
+            .filter(|gsi| route_capabilities & (1 << gsi) != 0)
+            .find(|&gsi| apic::route_gsi(gsi, vector))
+        {
+            Some(gsi) => gsi,
+            None => return,
+        };
+
+        // edge triggered, one-shot (periodic mode is bit 3), 64 bit comparator
+        let config = (config & !(0x1f << TIMER_ROUTE_SHIFT) & !(1 << 3) & !(1 << 1))
+            | ((gsi as u64) << TIMER_ROUTE_SHIFT)
+            | TIMER_INTERRUPT_ENABLE;
+        write(timer_comparator(EVENT_TIMER), u64::MAX);
+        write(timer_configuration(EVENT_TIMER), config);
+    }
+    EVENT_TIMER_ENABLED.store(true, Ordering::Relaxed);
+}
+
+// Returns whether the HPET is the clocksource of `time::now`.
+pub fn is_enabled() -> bool {
+    BASE.load(Ordering::Relaxed) != 0 && PERIOD_FS.load(Ordering::Relaxed) != 0
+}
+
+// Returns the value of the main counter, or 0 if the HPET is not enabled.
+pub fn counter() -> u64 {
+    if !is_enabled() {
+        return 0;
+    }
+    unsafe { read(MAIN_COUNTER) }
+}
+
+fn counter_to_nanos(counter: u64) -> u64 {
+    (counter as u128 * PERIOD_FS.load(Ordering::Relaxed) as u128 / 1_000_000) as u64
+}
+
+fn nanos_to_counter(nanos: u64) -> u64 {
+    (nanos as u128 * 1_000_000 / PERIOD_FS.load(Ordering::Relaxed).max(1) as u128) as u64
+}
+
+// Returns the current instant read from the main counter, or `None` if the HPET is not enabled.
+pub(super) fn now() -> Option<Instant> {
+    if !is_enabled() {
+        return None;
+    }
+    let nanos = BASE_NANOS.load(Ordering::Relaxed) + counter_to_nanos(counter());
+    Some(Instant::from_uptime_nanos(nanos))
+}
+
+// Returns whether timer 0 is set up to fire at the deadlines of the async timers.
+pub fn event_timer_enabled() -> bool {
+    EVENT_TIMER_ENABLED.load(Ordering::Relaxed)
+}
+
+// Makes timer 0 raise an interrupt at `deadline`. If the deadline already passed, no interrupt is
+// raised, the periodic timer interrupt then picks up the expired timers.
+pub(crate) fn arm_event_timer(deadline: Instant) {
+    if !event_timer_enabled() {
+        return;
+    }
+    let nanos = deadline
+        .as_uptime_nanos()
+        .saturating_sub(BASE_NANOS.load(Ordering::Relaxed));
+    unsafe { write(timer_comparator(EVENT_TIMER), nanos_to_counter(nanos)) };
+}
@@ -0,0 +1,78 @@
+use x86_64::instructions::port::Port;
+
+use crate::sync::IrqSafeMutex;
+
+// The PIT (Programmable Interval Timer, Intel 8253/8254) has three channels that count down from a
+// programmable reload value at a fixed input frequency. Channel 0 is connected to IRQ 0, channel 2
+// to the PC speaker (its output can be read back through port 0x61).
+
+// input frequency of the PIT in Hz
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+// controls the gate of channel 2 (bit 0) and the speaker (bit 1), bit 5 reflects the output of
+// channel 2
+const SPEAKER_CONTROL: u16 = 0x61;
+
+// Serializes the accesses to the command port (shared by all channels) and to channel 2, whose
+// count would otherwise be reprogrammed by another CPU in the middle of a `busy_wait_us`.
+static PIT: IrqSafeMutex<()> = IrqSafeMutex::new(());
+
+// Programs channel 0 to raise IRQ 0 `frequency` times per second and returns the frequency that
+// was actually set, since the reload value is an integer divisor of `BASE_FREQUENCY`.
+pub fn set_frequency(frequency: u32) -> u32 {
+    let divisor = reload_value(frequency);
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut channel0: Port<u8> = Port::new(CHANNEL_0);
+    let _pit = PIT.lock();
+    unsafe {
+        // channel 0, low byte then high byte, mode 2 (rate generator), binary
+        command.write(0b0011_0100);
+        channel0.write(divisor as u8);
+        channel0.write((divisor >> 8) as u8);
+    }
+    // a reload value of 0 stands for 65536
+    let divisor = if divisor == 0 { 65536 } else { divisor as u32 };
+    BASE_FREQUENCY / divisor
+}
+
+// Busy waits for the given number of microseconds (at most ~54ms) using channel 2, which is not
+// connected to any interrupt line and can therefore be used while the interrupts are disabled.
+//
+// Only one CPU can use channel 2 at a time, the others wait for it. Interrupts are disabled while
+// waiting.
+pub fn busy_wait_us(micros: u32) {
+    let count = (BASE_FREQUENCY as u64 * micros as u64 / 1_000_000).clamp(1, 0xffff) as u16;
+
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut channel2: Port<u8> = Port::new(CHANNEL_2);
+    let mut gate: Port<u8> = Port::new(SPEAKER_CONTROL);
+    let _pit = PIT.lock();
+    unsafe {
+        // enable the gate of channel 2 but keep the speaker disconnected
+        let value = gate.read();
+        gate.write((value & !0b10) | 0b01);
+
+        // channel 2, low byte then high byte, mode 0 (interrupt on terminal count)
+        command.write(0b1011_0000);
+        channel2.write(count as u8);
+        channel2.write((count >> 8) as u8);
+
+        // the output goes high once the count reaches zero
+        while gate.read() & 0b10_0000 == 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+fn reload_value(frequency: u32) -> u16 {
+    let divisor = BASE_FREQUENCY / frequency.max(1);
+    if divisor > 0xffff {
+        // the slowest rate the PIT supports (~18.2 Hz)
+        0
+    } else {
+        divisor.max(1) as u16
+    }
+}
@@ -0,0 +1,165 @@
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use super::{pit, Duration, Instant};
+
+// The time stamp counter (TSC) is a 64 bit register that counts CPU cycles since reset. It can be
+// read with a single instruction, which makes it the cheapest high resolution clock available.
+//
+// Older CPUs change the rate of the TSC together with the clock speed, only an "invariant" TSC
+// ticks at a constant rate and can be used to measure time.
+
+// TSC cycles per second, 0 until `init` calibrated the TSC
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+// TSC value and uptime at the end of the calibration, used to convert TSC values to instants
+static BASE_CYCLES: AtomicU64 = AtomicU64::new(0);
+static BASE_NANOS: AtomicU64 = AtomicU64::new(0);
+// `is_invariant`, checked once by `init` since CPUID is slow (and traps to the hypervisor in a VM)
+static INVARIANT: AtomicBool = AtomicBool::new(false);
+
+// Returns whether the CPU has a TSC (CPUID leaf 1, EDX bit 4).
+pub fn is_supported() -> bool {
+    let result = unsafe { __cpuid(1) };
+    result.edx & (1 << 4) != 0
+}
+
+// Returns whether the TSC runs at a constant rate regardless of power states (CPUID leaf
+// 0x80000007, EDX bit 8).
+pub fn is_invariant() -> bool {
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_extended_leaf < 0x8000_0007 {
+        return false;
+    }
+    let result = unsafe { __cpuid(0x8000_0007) };
+    result.edx & (1 << 8) != 0
+}
+
+// Returns the current value of the TSC.
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+// Measures the frequency of the TSC by counting the cycles that pass during a 50ms PIT wait.
+//
+// Must be called with interrupts disabled, an interrupt during the measurement would make the
+// TSC look faster than it is.
+pub fn init() {
+    if !is_supported() {
+        return;
+    }
+    INVARIANT.store(is_invariant(), Ordering::Relaxed);
+    let start = read();
+    pit::busy_wait_us(50_000);
+    let cycles = read() - start;
+    set_frequency(cycles * 20);
+}
+
+// Sets the TSC frequency, e.g. after a more precise calibration against the HPET.
+pub(crate) fn set_frequency(cycles_per_second: u64) {
+    BASE_CYCLES.store(read(), Ordering::Relaxed);
+    BASE_NANOS.store(super::now().as_uptime_nanos(), Ordering::Relaxed);
+    FREQUENCY.store(cycles_per_second, Ordering::Relaxed);
+}
+
+// Returns the calibrated TSC frequency in Hz, or `None` if the TSC was not calibrated.
+pub fn frequency() -> Option<u64> {
+    match FREQUENCY.load(Ordering::Relaxed) {
+        0 => None,
+        frequency => Some(frequency),
+    }
+}
+
+// Converts a number of TSC cycles into a duration. Returns zero if the TSC was not calibrated.
+pub fn cycles_to_duration(cycles: u64) -> Duration {
+    match frequency() {
+        Some(frequency) => {
+            Duration::from_nanos((cycles as u128 * 1_000_000_000 / frequency as u128) as u64)
+        }
+        None => Duration::ZERO,
+    }
+}
+
+// Returns the current instant with nanosecond resolution, or `None` if the TSC is not invariant
+// or was not calibrated.
+pub(super) fn now() -> Option<Instant> {
+    if !INVARIANT.load(Ordering::Relaxed) {
+        return None;
+    }
+    frequency()?;
+    let elapsed = read().wrapping_sub(BASE_CYCLES.load(Ordering::Relaxed));
+    let nanos = BASE_NANOS.load(Ordering::Relaxed) + cycles_to_duration(elapsed).as_nanos() as u64;
+    Some(Instant::from_uptime_nanos(nanos))
+}
+
+// Accumulates how many TSC cycles a piece of code takes, for benchmarking hot paths.
+//
+// Counters are meant to be declared as statics, e.g.
+//
+//     static POLL_CYCLES: CycleCounter = CycleCounter::new();
+//     let _measure = POLL_CYCLES.start();
+pub struct CycleCounter {
+    count: AtomicU64,
+    total: AtomicU64,
+    max: AtomicU64,
+}
+
+impl CycleCounter {
+    pub const fn new() -> Self {
+        CycleCounter {
+            count: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    // Starts a measurement that is recorded when the returned guard is dropped.
+    pub fn start(&self) -> CycleMeasurement {
+        CycleMeasurement {
+            counter: self,
+            start: read(),
+        }
+    }
+
+    pub fn record(&self, cycles: u64) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total.fetch_add(cycles, Ordering::Relaxed);
+        self.max.fetch_max(cycles, Ordering::Relaxed);
+    }
+
+    pub fn reset(&self) {
+        self.count.store(0, Ordering::Relaxed);
+        self.total.store(0, Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+    }
+
+    // number of recorded measurements
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    // sum of all recorded cycles
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    // the longest recorded measurement in cycles
+    pub fn max(&self) -> u64 {
+        self.max.load(Ordering::Relaxed)
+    }
+
+    pub fn average(&self) -> u64 {
+        self.total() / self.count().max(1)
+    }
+}
+
+// Guard returned by `CycleCounter::start`.
+pub struct CycleMeasurement<'a> {
+    counter: &'a CycleCounter,
+    start: u64,
+}
+
+impl Drop for CycleMeasurement<'_> {
+    fn drop(&mut self) {
+        self.counter.record(read().wrapping_sub(self.start));
+    }
+}
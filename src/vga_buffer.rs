@@ -2,6 +2,7 @@ use core::fmt::Write;
 use lazy_static::lazy_static;
 use spin::Mutex;
 use volatile::Volatile;
+use x86_64::instructions::port::Port;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(dead_code)]
@@ -41,8 +42,14 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
+
+// registers of the CRT controller, which draws the blinking hardware cursor
+const CRTC_INDEX_PORT: u16 = 0x3d4;
+const CRTC_DATA_PORT: u16 = 0x3d5;
+const CURSOR_LOCATION_HIGH: u8 = 0x0e;
+const CURSOR_LOCATION_LOW: u8 = 0x0f;
 
 #[repr(transparent)]
 struct Buffer {
@@ -55,6 +62,7 @@ struct Buffer {
 // the runtime and not compile time.
 lazy_static! {
     pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+        row_position: BUFFER_HEIGHT - 1,
         column_position: 0,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
@@ -83,6 +91,8 @@ pub fn _print(args: core::fmt::Arguments) {
 }
 
 pub struct Writer {
+    // the output normally goes to the last row, only `set_position` moves it somewhere else
+    row_position: usize,
     column_position: usize,
     color_code: ColorCode,
     buffer: &'static mut Buffer,
@@ -99,12 +109,13 @@ impl Writer {
     pub fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
+            0x08 => self.backspace(),
             byte => {
                 if self.column_position >= BUFFER_WIDTH {
                     self.new_line();
                 }
 
-                let row = BUFFER_HEIGHT - 1;
+                let row = self.row_position;
                 let col = self.column_position;
 
                 let color_code = self.color_code;
@@ -116,7 +127,46 @@ impl Writer {
             }
         }
     }
+    // Moves back one character, to the end of the previous row at the start of a row, and
+    // blanks it.
+    pub fn backspace(&mut self) {
+        if self.column_position > 0 {
+            self.column_position -= 1;
+        } else if self.row_position > 0 {
+            self.row_position -= 1;
+            self.column_position = BUFFER_WIDTH - 1;
+        } else {
+            return;
+        }
+        self.buffer.chars[self.row_position][self.column_position].write(ScreenChar {
+            ascii_code: b' ',
+            color_code: self.color_code,
+        });
+        self.update_cursor();
+    }
+    // Returns where the next character goes as (row, column). The column is `BUFFER_WIDTH` when
+    // the row is full, the next character then starts a new row.
+    pub fn position(&self) -> (usize, usize) {
+        (self.row_position, self.column_position)
+    }
+    // Moves the output to the given row and column, without changing the text on the screen. A
+    // newline or a full row below the last row still scrolls the screen.
+    pub fn set_position(&mut self, row: usize, column: usize) {
+        assert!(
+            row < BUFFER_HEIGHT && column <= BUFFER_WIDTH,
+            "position outside of the screen"
+        );
+        self.row_position = row;
+        self.column_position = column;
+        self.update_cursor();
+    }
     fn new_line(&mut self) {
+        // above the last row, the next row already exists
+        if self.row_position < BUFFER_HEIGHT - 1 {
+            self.row_position += 1;
+            self.column_position = 0;
+            return;
+        }
         for row in 1..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
                 let character = self.buffer.chars[row][col].read();
@@ -135,14 +185,28 @@ impl Writer {
             self.buffer.chars[row][col].write(blank);
         }
     }
+    // Moves the hardware cursor to where the next character goes.
+    fn update_cursor(&self) {
+        let position = (self.row_position * BUFFER_WIDTH + self.column_position)
+            .min(BUFFER_HEIGHT * BUFFER_WIDTH - 1) as u16;
+        let mut index = Port::<u8>::new(CRTC_INDEX_PORT);
+        let mut data = Port::<u8>::new(CRTC_DATA_PORT);
+        unsafe {
+            index.write(CURSOR_LOCATION_HIGH);
+            data.write((position >> 8) as u8);
+            index.write(CURSOR_LOCATION_LOW);
+            data.write(position as u8);
+        }
+    }
     pub fn write_string(&mut self, s: &str) {
         for byte in s.bytes() {
             match byte {
-                // printable ASCII byte or newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
+                // printable ASCII byte, newline or backspace
+                0x20..=0x7e | b'\n' | 0x08 => self.write_byte(byte),
                 // not part of printable ASCII range
                 _ => self.write_byte(0xfe),
             }
         }
+        self.update_cursor();
     }
 }